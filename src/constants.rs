@@ -1,4 +1,36 @@
 pub(crate) const FETCH_RETRY_SLEEP_DELAY: i32 = 50; // milliseconds
-pub(crate) const INIT_TUNNEL_RETRY_SLEEP_DELAY: i32 = 1000; // milliseconds
-pub(crate) const FETCH_RETRY_ATTEMPTS: u32 = 3; // maximum attempts to reinitialize the tunnel
-pub(crate) const INIT_TUNNEL_RETRY_ATTEMPTS: u32 = 3; // maximum attempts to send init_tunnel request
+pub(crate) const INIT_TUNNEL_RETRY_BASE_DELAY: i32 = 1000; // milliseconds, doubled on every failed attempt
+pub(crate) const INIT_TUNNEL_RETRY_MAX_DELAY: i32 = 8000; // milliseconds, upper bound of the backoff before jitter
+pub(crate) const CONNECTING_WAIT_TIMEOUT: u32 = 30_000; // milliseconds, default maximum wait for a CONNECTING tunnel
+pub(crate) const FETCH_RETRY_ATTEMPTS: u32 = 3; // default maximum attempts to reinitialize the tunnel, see `fetchRetries`
+pub(crate) const INIT_TUNNEL_RETRY_ATTEMPTS: u32 = 3; // default maximum attempts to send init_tunnel request, see `initRetries`
+pub(crate) const IDEMPOTENT_HTTP_METHODS: [&str; 5] = ["GET", "HEAD", "PUT", "DELETE", "OPTIONS"]; // methods queued while offline without opting in, see `queueOffline`
+pub(crate) const STANDARD_HTTP_METHODS: [&str; 9] = [
+    "GET", "HEAD", "POST", "PUT", "PATCH", "DELETE", "OPTIONS", "TRACE", "CONNECT",
+]; // methods accepted without being listed in `customMethods`
+pub(crate) const CORS_SAFELISTED_METHODS: [&str; 3] = ["GET", "HEAD", "POST"]; // methods sent without a CORS preflight, see `emulatePreflight`
+pub(crate) const CORS_SAFELISTED_HEADERS: [&str; 4] = [
+    "accept",
+    "accept-language",
+    "content-language",
+    "content-type",
+]; // headers sent without a CORS preflight, `content-type` only with a `CORS_SAFELISTED_CONTENT_TYPES` value
+pub(crate) const CORS_SAFELISTED_CONTENT_TYPES: [&str; 3] = [
+    "application/x-www-form-urlencoded",
+    "multipart/form-data",
+    "text/plain",
+]; // content types sent without a CORS preflight
+pub(crate) const TUNNEL_ERROR_STATUSES: [u16; 3] = [502, 503, 504]; // proxy statuses reinitializing the tunnel, any other error status is handed over
+pub(crate) const TUNNEL_EXPIRED_HEADER: &str = "x-l8-tunnel-expired"; // set by the proxy on the responses of an expired tunnel, whatever their status
+pub(crate) const STREAM_PREALLOCATION_LIMIT: usize = 16 * 1024 * 1024; // most bytes allocated up front for a stream body from its size hint, wasm memory never shrinks
+pub(crate) const KEEPALIVE_BODY_LIMIT: usize = 64 * 1024; // bytes browsers allow the in-flight keepalive requests of a page
+pub(crate) const SHARED_SECRET_FINGERPRINT_BYTES: usize = 8; // bytes of the SHA-256 of the shared secret kept in its fingerprint
+pub(crate) const ERROR_BODY_PREVIEW_BYTES: usize = 64; // bytes of a binary error body shown in hex in the error message
+pub(crate) const REQUEST_METRICS_HISTORY: usize = 64; // number of recent request metrics kept in memory
+pub(crate) const RESPONSE_CACHE_CAPACITY: usize = 64; // number of GET/HEAD responses kept in the in-memory response cache
+pub(crate) const IDB_DATABASE_NAME: &str = "layer8"; // IndexedDB database holding the persisted object stores
+pub(crate) const IDB_METADATA_STORE: &str = "l8_metadata"; // object store tracking the size and last access of every entry
+pub(crate) const IDB_REQUEST_LOG_STORE: &str = "l8_request_log"; // object store holding the persisted request logs
+pub(crate) const IDB_REQUEST_QUEUE_STORE: &str = "l8_request_queue"; // object store holding the requests queued while offline
+pub(crate) const REQUEST_QUEUE_CAPACITY: u32 = 100; // number of requests the offline queue holds, the next ones fail
+pub(crate) const QUEUE_WAIT_TIMEOUT: u32 = 300_000; // milliseconds, default maximum wait of a queued request for its replay
@@ -1,14 +1,18 @@
 use wasm_bindgen::prelude::*;
-use web_sys::{RequestInit, console};
+use web_sys::RequestInit;
 
-use crate::init_tunnel::init_tunnel;
+use crate::init_tunnel::init_tunnel_with_failover;
 use crate::storage::InMemoryCache;
 use crate::types::{
-    http_caller::ActualHttpCaller,
-    network_state::{NetworkStateOpen, NetworkStateResponse},
+    error::L8Error,
+    http_caller::{ActualHttpCaller, HttpCaller},
+    log_level::LogLevel,
+    metrics::{RequestLogEntry, RequestMetrics, RequestTiming},
+    network_state::{NetworkStateErrored, NetworkStateOpen, NetworkStateResponse},
     request::L8RequestObject,
+    response::{L8ResponseObject, StoredResponse},
 };
-use crate::{constants, utils};
+use crate::{indexeddb, queue, utils};
 
 /// This API is expected to be a 1:1 mapping of the Fetch API.
 /// Arguments:
@@ -19,59 +23,385 @@ pub async fn fetch(
     resource: JsValue,
     options: Option<RequestInit>,
 ) -> Result<web_sys::Response, JsValue> {
-    let dev_flag = InMemoryCache::get_dev_flag();
+    fetch_with(resource, options, ActualHttpCaller).await
+}
+
+/// `fetch` with `http_caller` reaching the proxy, for the requests and the handshakes reinitializing the tunnel
+/// (e.g. a `MockProxy` to test the whole fetch).
+pub async fn fetch_with(
+    resource: JsValue,
+    options: Option<RequestInit>,
+    http_caller: impl HttpCaller + 'static,
+) -> Result<web_sys::Response, JsValue> {
+    fetch_response(resource, options, http_caller)
+        .await?
+        .into_js_response()
+}
+
+/// Like `fetch`, but saves the response body under `key` in the `store` IndexedDB object store instead of returning
+/// the response, for download-to-disk flows. The decrypted body of a tunneled response is written as is, it is never
+/// copied into a JS `Response` first. Resolves with the `StoredResponse` metadata.
+#[wasm_bindgen(js_name = "fetchToStore")]
+pub async fn fetch_to_store(
+    resource: JsValue,
+    options: Option<RequestInit>,
+    store: String,
+    key: String,
+) -> Result<JsValue, JsValue> {
+    fetch_to_store_with(resource, options, store, key, ActualHttpCaller).await
+}
+
+/// `fetchToStore` with `http_caller` reaching the proxy, see `fetch_with`.
+pub async fn fetch_to_store_with(
+    resource: JsValue,
+    options: Option<RequestInit>,
+    store: String,
+    key: String,
+    http_caller: impl HttpCaller + 'static,
+) -> Result<JsValue, JsValue> {
+    let (status, content_type, body) = match fetch_response(resource, options, http_caller).await? {
+        FetchedResponse::Decrypted(response) => (
+            response.status,
+            response.header("content-type"),
+            response.body,
+        ),
+        FetchedResponse::Js(response) => {
+            let body = match response.body() {
+                Some(body) => utils::readable_stream_to_bytes(body, None, None).await?,
+                None => Vec::new(),
+            };
+            (
+                response.status(),
+                response.headers().get("content-type")?,
+                body,
+            )
+        }
+    };
+
+    let size = body.len();
+    indexeddb::idb_put(store.clone(), key.clone(), body).await?;
+
+    let stored = StoredResponse {
+        store,
+        key,
+        status,
+        content_type,
+        size,
+    };
+    serde_wasm_bindgen::to_value(&stored)
+        .map_err(|e| JsValue::from_str(&format!("Failed to serialize stored response: {}", e)))
+}
+
+/// What `fetch` resolves with, before it becomes a JS `Response`.
+enum FetchedResponse {
+    /// Decrypted from the tunnel, or served from the response cache.
+    Decrypted(L8ResponseObject),
+    /// Handed over by JS: the response of the native `fetch` to a provider without a tunnel, or of a queued request
+    /// replayed later.
+    Js(web_sys::Response),
+}
+
+impl FetchedResponse {
+    fn status(&self) -> u16 {
+        match self {
+            FetchedResponse::Decrypted(response) => response.status,
+            FetchedResponse::Js(response) => response.status(),
+        }
+    }
+
+    fn into_js_response(self) -> Result<web_sys::Response, JsValue> {
+        match self {
+            FetchedResponse::Decrypted(response) => response.reconstruct_js_response(),
+            FetchedResponse::Js(response) => Ok(response),
+        }
+    }
+}
+
+async fn fetch_response(
+    resource: JsValue,
+    options: Option<RequestInit>,
+    http_caller: impl HttpCaller + 'static,
+) -> Result<FetchedResponse, JsValue> {
+    if InMemoryCache::get_passthrough_uninitialized() {
+        let init = options.as_ref().map_or(JsValue::UNDEFINED, JsValue::from);
+        if !has_tunnel(&resource, &init) {
+            let response =
+                wasm_bindgen_futures::JsFuture::from(native_fetch(&resource, &init)).await?;
+            return Ok(FetchedResponse::Js(response.unchecked_into()));
+        }
+    }
+
+    let Some(capacity) = InMemoryCache::get_request_log_capacity() else {
+        return tunneled_fetch(resource, options, None, http_caller).await;
+    };
+
+    let mut log_entry =
+        RequestLogEntry::start(&utils::retrieve_resource_url(&resource).unwrap_or_default());
+    let result = tunneled_fetch(resource, options, Some(&mut log_entry), http_caller).await;
+    // awaited rather than spawned so that the logs are in order, this only runs in dev mode
+    log_entry
+        .persist(result.as_ref().map(FetchedResponse::status), capacity)
+        .await;
+    result
+}
+
+/// Replaces `globalThis.fetch` so that the plain `fetch` calls of the app go through the tunnel of their provider.
+/// Requests to a provider that has not been initialized are left to the original `fetch`. Installing it twice is a
+/// no-op, see `uninstallGlobalFetch` to restore the original.
+#[wasm_bindgen(js_name = "installGlobalFetch")]
+pub fn install_global_fetch() -> Result<(), JsValue> {
+    if InMemoryCache::get_original_fetch().is_some() {
+        return Ok(());
+    }
+
+    let global = js_sys::global();
+    let original = js_sys::Reflect::get(&global, &"fetch".into())?
+        .dyn_into::<js_sys::Function>()
+        .map_err(|_| JsValue::from_str("There is no global fetch to replace"))?;
+
+    let patched = Closure::<dyn Fn(JsValue, JsValue) -> js_sys::Promise>::new(
+        |resource: JsValue, init: JsValue| {
+            if !has_tunnel(&resource, &init) {
+                return native_fetch(&resource, &init);
+            }
+
+            // `RequestInit` is a dictionary, there is no class to check it against
+            let options = init
+                .is_object()
+                .then(|| init.unchecked_into::<RequestInit>());
+            wasm_bindgen_futures::future_to_promise(async move {
+                fetch(resource, options).await.map(JsValue::from)
+            })
+        },
+    );
+
+    js_sys::Reflect::set(&global, &"fetch".into(), &patched.into_js_value())?;
+    InMemoryCache::set_original_fetch(Some(original));
+    Ok(())
+}
+
+/// Restores the `globalThis.fetch` replaced by `installGlobalFetch`, if any.
+#[wasm_bindgen(js_name = "uninstallGlobalFetch")]
+pub fn uninstall_global_fetch() -> Result<(), JsValue> {
+    if let Some(original) = InMemoryCache::set_original_fetch(None) {
+        js_sys::Reflect::set(&js_sys::global(), &"fetch".into(), &original)?;
+    }
+
+    Ok(())
+}
+
+/// Whether the request targets a provider initialized with `initEncryptedTunnel`. Relative urls are resolved against
+/// the document base first, they go through the tunnel when the page itself is a provider.
+fn has_tunnel(resource: &JsValue, init: &JsValue) -> bool {
+    let Some(base_url) = utils::retrieve_resource_url(resource)
+        .and_then(|url| utils::get_base_url(&url))
+        .ok()
+    else {
+        return false;
+    };
+
+    let session_key = js_sys::Reflect::get(init, &"sessionKey".into())
+        .ok()
+        .and_then(|key| key.as_string());
+    InMemoryCache::has_network_state(&utils::session_cache_key(&base_url, session_key.as_deref()))
+}
+
+/// Calls the browser's own `fetch`, the one replaced by `installGlobalFetch` if it is installed.
+pub(crate) fn native_fetch(resource: &JsValue, init: &JsValue) -> js_sys::Promise {
+    let global = js_sys::global();
+    let fetch = InMemoryCache::get_original_fetch().or_else(|| {
+        js_sys::Reflect::get(&global, &"fetch".into())
+            .ok()
+            .and_then(|fetch| fetch.dyn_into::<js_sys::Function>().ok())
+    });
+
+    let Some(fetch) = fetch else {
+        return js_sys::Promise::reject(
+            &L8Error::NotInitialized("There is no global fetch to call".to_string()).into(),
+        );
+    };
+
+    // `fetch` throws an "Illegal invocation" when it is not called on the global object
+    match fetch.call2(&global, resource, init) {
+        Ok(promise) => promise.unchecked_into(),
+        Err(err) => js_sys::Promise::reject(&err),
+    }
+}
+
+async fn tunneled_fetch(
+    resource: JsValue,
+    options: Option<RequestInit>,
+    log_entry: Option<&mut RequestLogEntry>,
+    http_caller: impl HttpCaller + 'static,
+) -> Result<FetchedResponse, JsValue> {
     let backend_url = utils::retrieve_resource_url(&resource)?;
     let backend_base_url = utils::get_base_url(&backend_url)?;
 
-    let req_object = L8RequestObject::new(backend_url, resource, options).await?;
+    // only measured when someone listens
+    let mut timing =
+        InMemoryCache::get_on_timing().map(|_| RequestTiming::start(&backend_base_url));
+    let clock = |timed: bool| if timed { js_sys::Date::now() } else { 0.0 };
 
-    // we can limit the reinitialization to 2 per fetch call and +1 for the initial request
-    let mut attempts = constants::FETCH_RETRY_ATTEMPTS;
+    let mut req_object = L8RequestObject::new(backend_url.clone(), resource, options).await?;
+    req_object.validate()?;
+    req_object.check_route(&backend_base_url)?;
+    // decided on the headers of the caller, before the interceptor adds its own
+    let mut preflight = if InMemoryCache::get_emulate_preflight() {
+        req_object.preflight_request(&backend_base_url, utils::global_origin().as_deref())
+    } else {
+        None
+    };
+    req_object.set_content_length();
+    req_object.apply_credentials(
+        &backend_base_url,
+        &utils::document_cookie(),
+        utils::global_origin().as_deref(),
+    );
+    if let Some(log_entry) = log_entry {
+        log_entry.method = req_object.method.clone();
+    }
+    let network_state_key = req_object.network_state_key(&backend_base_url);
+
+    // a usable cached response skips the tunnel altogether, a stale one is revalidated with its `ETag`
+    req_object.response_cache_key = req_object.response_cache_key(&network_state_key);
+    if let Some(cached) = req_object.use_cached_response() {
+        return Ok(FetchedResponse::Decrypted(cached));
+    }
+
+    // last, so that no header set above escapes the provider's header lists
+    req_object.apply_header_policy(&backend_base_url);
+
+    // we can limit the reinitialization to `fetchRetries - 1` per fetch call and +1 for the initial request
+    let retries = InMemoryCache::get_fetch_retries();
+    let mut attempts = retries;
     loop {
-        let network_state_open = InMemoryCache::get_network_state(&backend_base_url).await?;
+        // an aborted request neither handshakes nor sends again
+        req_object.check_aborted()?;
+
+        let handshake_start = clock(timing.is_some());
+        let network_state_open =
+            match InMemoryCache::get_network_state(&network_state_key, http_caller.clone()).await {
+                Ok(network_state_open) => network_state_open,
+                Err(err) if queue::should_queue(&req_object, &network_state_key) => {
+                    return queue::enqueue(&network_state_key, &backend_url, &req_object, err)
+                        .await
+                        .map(FetchedResponse::Js);
+                }
+                Err(err) => return Err(err),
+            };
+
+        // sent once, before the first attempt of the actual request
+        if let Some(preflight) = preflight.take() {
+            let response = preflight
+                .send_through(&network_state_open, http_caller.clone())
+                .await?;
+            preflight.check_preflight_response(&response)?;
+        }
 
+        // `noRetry` requests fail fast rather than paying for a handshake
+        let reinitialize_attempt = attempts > 0 && !req_object.no_retry;
+        let attempt = retries - attempts + 1;
+        let request_start = clock(timing.is_some());
+        let mut metrics = RequestMetrics::default();
         let resp = req_object
-            .l8_send(&network_state_open, attempts > 0)
+            .l8_send(
+                &network_state_open,
+                http_caller.clone(),
+                reinitialize_attempt,
+                attempt,
+                &mut metrics,
+            )
             .await?;
 
+        if let Some(timing) = timing.as_mut() {
+            timing.handshake_ms += request_start - handshake_start;
+            // `decrypt_ms` has a finer resolution than `Date.now()`
+            timing.request_ms +=
+                (js_sys::Date::now() - request_start - metrics.decrypt_ms).max(0.0);
+            timing.decrypt_ms += metrics.decrypt_ms;
+        }
+
         // we decrement the attempts, incase we have reinitialized the network state
-        attempts -= 1;
+        attempts = attempts.saturating_sub(1);
         match resp {
             NetworkStateResponse::ProviderResponse(response) => {
                 // If the response is successful, we return it
-                return Ok(response);
+                if let Some(timing) = timing {
+                    timing.report();
+                }
+
+                return Ok(FetchedResponse::Decrypted(response));
             }
 
             NetworkStateResponse::ProxyError(err) => {
                 // If the response is an error, we have exhausted the reinitialization attempts
-                if dev_flag {
-                    console::error_1(&err);
-                }
+                InMemoryCache::log(LogLevel::Warn, &err);
 
                 return Err(err);
             }
 
             NetworkStateResponse::Reinitialize => {
-                let backend_url = format!(
-                    "{}/init-tunnel?backend_url={}",
-                    network_state_open.forward_proxy_url, backend_base_url
+                // aborted during the failed send, nobody waits for the handshake anymore
+                req_object.check_aborted()?;
+
+                // another fetch is already reinitializing this provider, we wait for its result
+                // through `get_network_state` on the next iteration instead of handshaking again
+                if !InMemoryCache::try_begin_init(&network_state_key) {
+                    continue;
+                }
+
+                InMemoryCache::log(
+                    LogLevel::Info,
+                    &format!("Reinitializing network state for {}", network_state_key).into(),
                 );
 
-                if dev_flag {
-                    console::log_1(
-                        &format!("Reinitializing network state for {}", backend_url).into(),
-                    );
+                // concurrent fetches wait on CONNECTING until the handshake completes
+                InMemoryCache::set_connecting_network_state(&network_state_key);
+
+                // creating a new NetworkState and overwriting the existing one, the http client is
+                // carried over so the connection pool survives the reinitialization
+                let http_client = network_state_open.http_client.clone();
+                let handshake_start = clock(timing.is_some());
+                // the proxy that just failed is tried last, the next one of the failover list first
+                let forward_proxy_urls = InMemoryCache::get_forward_proxy_urls(
+                    &network_state_open.forward_proxy_url,
+                    true,
+                );
+                let result = init_tunnel_with_failover(
+                    &forward_proxy_urls,
+                    &backend_base_url,
+                    &http_client,
+                    http_caller.clone(),
+                )
+                .await;
+                InMemoryCache::end_init(&network_state_key);
+
+                if let Some(timing) = timing.as_mut() {
+                    timing.handshake_ms += js_sys::Date::now() - handshake_start;
                 }
 
-                // creating a new NetworkState and overwriting the existing one
-                let val = init_tunnel(backend_url, ActualHttpCaller).await?;
-                let state = NetworkStateOpen {
-                    http_client: reqwest::Client::new(),
-                    init_tunnel_result: val.clone(),
-                    forward_proxy_url: network_state_open.forward_proxy_url.clone(),
-                };
+                match result {
+                    Ok((val, forward_proxy_url)) => {
+                        let state = NetworkStateOpen {
+                            http_client,
+                            init_tunnel_result: val,
+                            forward_proxy_url,
+                        };
 
-                InMemoryCache::set_open_network_state(&backend_base_url, state);
+                        InMemoryCache::set_open_network_state(&network_state_key, state);
+                    }
+                    Err(err) => {
+                        let state = NetworkStateErrored {
+                            error: err.clone(),
+                            forward_proxy_url: network_state_open.forward_proxy_url.clone(),
+                            http_client,
+                            errored_at: js_sys::Date::now(),
+                        };
+
+                        InMemoryCache::set_errored_network_state(&network_state_key, state);
+                        return Err(err);
+                    }
+                }
             }
         }
     }
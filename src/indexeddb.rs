@@ -0,0 +1,632 @@
+use crate::constants::{IDB_DATABASE_NAME, IDB_METADATA_STORE};
+use crate::storage::InMemoryCache;
+use crate::utils;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::{JsCast, JsValue, prelude::Closure};
+use web_sys::{
+    IdbCursor, IdbDatabase, IdbFactory, IdbKeyRange, IdbObjectStore, IdbRequest, IdbTransactionMode,
+};
+
+/// Metadata kept for every entry, used to evict the least recently used entries.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct EntryMetadata {
+    store: String,
+    key: String,
+    size: f64,
+    last_access: f64,
+}
+
+/// Stores the bytes under `key` in the `store` object store, overwriting any previous value.
+/// The object store is created on first use.
+///
+/// Like the other key-value calls (`idb_get`, `idb_delete`, `idb_keys` and `idb_clear`), the value is only kept in
+/// memory when IndexedDB is unavailable, see `open_key_value_database`.
+///
+/// When the browser storage quota is exceeded, the least recently used entries are evicted (down to the
+/// `cacheMaxBytes` budget, or half of the stored bytes without one) and the write is retried once. With a
+/// budget, the entries past it are evicted after every write.
+pub async fn idb_put(store: String, key: String, value: Vec<u8>) -> Result<(), JsValue> {
+    let key = versioned_key(&key);
+    let Some(db) = open_key_value_database(&store, true).await? else {
+        InMemoryCache::idb_fallback_put(&store, &key, value);
+        return Ok(());
+    };
+
+    let size = value.len() as f64;
+    let value: JsValue = js_sys::Uint8Array::from(value.as_slice()).into();
+    let budget = InMemoryCache::get_cache_max_bytes();
+
+    let put =
+        |object_store: &IdbObjectStore| object_store.put_with_key(&value, &key.clone().into());
+    let result = run_request_in(&db, &store, IdbTransactionMode::Readwrite, put).await;
+    db.close();
+
+    if let Err(err) = result {
+        if !is_quota_exceeded(&err) {
+            return Err(err);
+        }
+
+        let stored_bytes = read_metadata()
+            .await?
+            .iter()
+            .map(|entry| entry.size)
+            .sum::<f64>();
+        idb_evict_to(budget.unwrap_or(stored_bytes / 2.0)).await?;
+        run_request(&store, IdbTransactionMode::Readwrite, put).await?;
+    }
+
+    touch_metadata(&store, &key, Some(size)).await?;
+
+    if let Some(budget) = budget {
+        idb_evict_to(budget).await?;
+    }
+
+    Ok(())
+}
+
+/// Reads the stream to its end and stores its bytes under `key` in the `store` object store, like `idb_put`.
+/// Returns the number of bytes stored.
+pub async fn idb_put_stream(
+    store: String,
+    key: String,
+    stream: web_sys::ReadableStream,
+) -> Result<usize, JsValue> {
+    let value = utils::readable_stream_to_bytes(stream, None, None).await?;
+    let size = value.len();
+    idb_put(store, key, value).await?;
+    Ok(size)
+}
+
+/// Returns the bytes stored under `key` in the `store` object store, `None` if there are none.
+pub async fn idb_get(store: String, key: String) -> Result<Option<Vec<u8>>, JsValue> {
+    let key = versioned_key(&key);
+    let Some(db) = open_key_value_database(&store, true).await? else {
+        return Ok(InMemoryCache::idb_fallback_get(&store, &key));
+    };
+
+    let value = run_request_in(&db, &store, IdbTransactionMode::Readonly, |object_store| {
+        object_store.get(&key.clone().into())
+    })
+    .await;
+    db.close();
+    let value = value?;
+
+    if value.is_undefined() || value.is_null() {
+        return Ok(None);
+    }
+
+    touch_metadata(&store, &key, None).await?;
+    Ok(Some(js_sys::Uint8Array::new(&value).to_vec()))
+}
+
+/// Removes the value stored under `key` in the `store` object store. Deleting a missing key is not an error.
+pub async fn idb_delete(store: String, key: String) -> Result<(), JsValue> {
+    let key = versioned_key(&key);
+    let Some(db) = open_key_value_database(&store, true).await? else {
+        InMemoryCache::idb_fallback_delete(&store, &key);
+        return Ok(());
+    };
+
+    let result = run_request_in(&db, &store, IdbTransactionMode::Readwrite, |object_store| {
+        object_store.delete(&key.clone().into())
+    })
+    .await;
+    db.close();
+    result?;
+
+    delete_metadata(&store, &key).await
+}
+
+/// Returns the keys stored with `idb_put` in the `store` object store under the current `cacheVersion`, in key
+/// order. Fails if the object store does not exist.
+pub async fn idb_keys(store: String) -> Result<Vec<String>, JsValue> {
+    let version = InMemoryCache::get_cache_version();
+    let Some(db) = open_key_value_database(&store, false).await? else {
+        return Ok(InMemoryCache::idb_fallback_keys(&store)
+            .iter()
+            .filter_map(|key| unversioned_key(key, &version))
+            .collect());
+    };
+    let keys = match db
+        .transaction_with_str(&store)
+        .and_then(|transaction| transaction.object_store(&store))
+        .and_then(|object_store| object_store.open_key_cursor())
+    {
+        Ok(request) => collect_cursor_keys(&request).await,
+        Err(err) => Err(err),
+    };
+    db.close();
+
+    Ok(keys?
+        .iter()
+        .filter_map(|key| key.as_string())
+        .filter_map(|key| unversioned_key(&key, &version))
+        .collect())
+}
+
+/// Removes every value of the `store` object store, in a single transaction, along with their metadata. Fails if
+/// the object store does not exist.
+pub async fn idb_clear(store: String) -> Result<(), JsValue> {
+    let Some(db) = open_key_value_database(&store, false).await? else {
+        InMemoryCache::idb_fallback_clear(&store);
+        return Ok(());
+    };
+    let result = run_request_in(&db, &store, IdbTransactionMode::Readwrite, |object_store| {
+        object_store.clear()
+    })
+    .await;
+    db.close();
+    result?;
+
+    // metadata keys are `[store, key]` arrays, and arrays sort after the string keys
+    let entries = IdbKeyRange::bound(
+        &js_sys::Array::of1(&store.as_str().into()),
+        &js_sys::Array::of2(&store.as_str().into(), &js_sys::Array::new()),
+    )?;
+    run_request(
+        IDB_METADATA_STORE,
+        IdbTransactionMode::Readwrite,
+        |object_store| object_store.delete(&entries),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Appends the value to the `store` object store, used as a ring buffer: the oldest values are dropped once it
+/// holds more than `capacity` of them. Unlike `idb_put`, appended values are neither versioned nor evicted.
+/// Returns the key of the value, see `idb_remove`.
+///
+/// Like the key-value calls, the appended values (and `idb_get_all`, `idb_get_all_entries`, `idb_remove` and
+/// `idb_take`) are only kept in memory when IndexedDB is unavailable.
+pub async fn idb_append(store: &str, value: &JsValue, capacity: u32) -> Result<JsValue, JsValue> {
+    // keys are increasing timestamps, so the store iterates in insertion order
+    let key = InMemoryCache::next_cache_access_time();
+    let Some(db) = open_key_value_database(store, true).await? else {
+        InMemoryCache::idb_fallback_append(store, key, value.clone(), capacity);
+        return Ok(key.into());
+    };
+
+    let result = append_in(&db, store, value, &key.into(), capacity).await;
+    db.close();
+    result?;
+
+    Ok(key.into())
+}
+
+async fn append_in(
+    db: &IdbDatabase,
+    store: &str,
+    value: &JsValue,
+    key: &JsValue,
+    capacity: u32,
+) -> Result<(), JsValue> {
+    run_request_in(db, store, IdbTransactionMode::Readwrite, |object_store| {
+        object_store.put_with_key(value, key)
+    })
+    .await?;
+
+    let keys = js_sys::Array::from(
+        &run_request_in(db, store, IdbTransactionMode::Readonly, |object_store| {
+            object_store.get_all_keys()
+        })
+        .await?,
+    );
+    let overflow = keys.length().saturating_sub(capacity);
+    if overflow == 0 {
+        return Ok(());
+    }
+
+    let oldest = IdbKeyRange::upper_bound(&keys.get(overflow - 1))?;
+    run_request_in(db, store, IdbTransactionMode::Readwrite, |object_store| {
+        object_store.delete(&oldest)
+    })
+    .await?;
+
+    Ok(())
+}
+
+/// Returns every value of the `store` object store, in key order.
+pub async fn idb_get_all(store: &str) -> Result<js_sys::Array, JsValue> {
+    let Some(db) = open_key_value_database(store, true).await? else {
+        return Ok(InMemoryCache::idb_fallback_entries(store)
+            .into_iter()
+            .map(|(_, value)| value)
+            .collect());
+    };
+
+    let values = run_request_in(&db, store, IdbTransactionMode::Readonly, |object_store| {
+        object_store.get_all()
+    })
+    .await;
+    db.close();
+
+    Ok(js_sys::Array::from(&values?))
+}
+
+/// Returns every key and value of the `store` object store, in key order.
+pub async fn idb_get_all_entries(store: &str) -> Result<Vec<(JsValue, JsValue)>, JsValue> {
+    let Some(db) = open_key_value_database(store, true).await? else {
+        return Ok(InMemoryCache::idb_fallback_entries(store)
+            .into_iter()
+            .map(|(key, value)| (key.into(), value))
+            .collect());
+    };
+
+    // both reads share a transaction, a removal landing in between would pair the keys with the wrong values
+    let requests = db
+        .transaction_with_str(store)
+        .and_then(|transaction| transaction.object_store(store))
+        .and_then(|object_store| Ok((object_store.get_all_keys()?, object_store.get_all()?)));
+    let entries = match requests {
+        Ok((keys, values)) => match (await_request(&keys).await, await_request(&values).await) {
+            (Ok(keys), Ok(values)) => Ok(js_sys::Array::from(&keys)
+                .iter()
+                .zip(js_sys::Array::from(&values).iter())
+                .collect()),
+            (Err(err), _) | (_, Err(err)) => Err(err),
+        },
+        Err(err) => Err(err),
+    };
+    db.close();
+
+    entries
+}
+
+/// Removes the value stored under the raw `key`, as returned by `idb_append` or `idb_get_all_entries`.
+pub async fn idb_remove(store: &str, key: &JsValue) -> Result<(), JsValue> {
+    idb_take(store, key).await?;
+    Ok(())
+}
+
+/// Removes the value stored under the raw `key` like `idb_remove`, and returns it, `None` when it was already gone.
+/// The read and the removal share a transaction, so of two concurrent calls for the same key only one gets the value.
+pub async fn idb_take(store: &str, key: &JsValue) -> Result<Option<JsValue>, JsValue> {
+    let Some(db) = open_key_value_database(store, true).await? else {
+        return Ok(key
+            .as_f64()
+            .and_then(|key| InMemoryCache::idb_fallback_remove(store, key)));
+    };
+
+    let requests = db
+        .transaction_with_str_and_mode(store, IdbTransactionMode::Readwrite)
+        .and_then(|transaction| transaction.object_store(store))
+        .and_then(|object_store| Ok((object_store.get(key)?, object_store.delete(key)?)));
+    let value = match requests {
+        Ok((get, delete)) => match (await_request(&get).await, await_request(&delete).await) {
+            (Ok(value), Ok(_)) => Ok(Some(value).filter(|value| !value.is_undefined())),
+            (Err(err), _) | (_, Err(err)) => Err(err),
+        },
+        Err(err) => Err(err),
+    };
+    db.close();
+
+    value
+}
+
+/// Deletes the least recently used entries, across all stores, until the stored values take at most
+/// `max_bytes` bytes.
+pub async fn idb_evict_to(max_bytes: f64) -> Result<(), JsValue> {
+    let mut entries = read_metadata().await?;
+    let mut stored_bytes = entries.iter().map(|entry| entry.size).sum::<f64>();
+
+    entries.sort_by(|a, b| a.last_access.total_cmp(&b.last_access));
+    for entry in entries {
+        if stored_bytes <= max_bytes {
+            break;
+        }
+
+        delete_entry(&entry.store, &entry.key).await?;
+        stored_bytes -= entry.size;
+    }
+
+    Ok(())
+}
+
+/// Deletes an entry (by its versioned key) along with its metadata.
+async fn delete_entry(store: &str, key: &str) -> Result<(), JsValue> {
+    run_request(store, IdbTransactionMode::Readwrite, |object_store| {
+        object_store.delete(&key.into())
+    })
+    .await?;
+
+    delete_metadata(store, key).await
+}
+
+async fn delete_metadata(store: &str, key: &str) -> Result<(), JsValue> {
+    run_request(
+        IDB_METADATA_STORE,
+        IdbTransactionMode::Readwrite,
+        |object_store| object_store.delete(&metadata_key(store, key)),
+    )
+    .await?;
+
+    Ok(())
+}
+
+/// Records an access to the entry, with its new size when it was written.
+async fn touch_metadata(store: &str, key: &str, size: Option<f64>) -> Result<(), JsValue> {
+    let metadata_key = metadata_key(store, key);
+    let size = match size {
+        Some(size) => size,
+        None => {
+            let current = run_request(
+                IDB_METADATA_STORE,
+                IdbTransactionMode::Readonly,
+                |object_store| object_store.get(&metadata_key),
+            )
+            .await?;
+
+            // entries written before the metadata existed are accounted for on their next write
+            match serde_wasm_bindgen::from_value::<EntryMetadata>(current) {
+                Ok(metadata) => metadata.size,
+                Err(_) => return Ok(()),
+            }
+        }
+    };
+
+    let metadata = EntryMetadata {
+        store: store.to_string(),
+        key: key.to_string(),
+        size,
+        last_access: InMemoryCache::next_cache_access_time(),
+    };
+    let metadata = serde_wasm_bindgen::to_value(&metadata)
+        .map_err(|e| JsValue::from_str(&format!("Failed to serialize entry metadata: {}", e)))?;
+
+    run_request(
+        IDB_METADATA_STORE,
+        IdbTransactionMode::Readwrite,
+        |object_store| object_store.put_with_key(&metadata, &metadata_key),
+    )
+    .await?;
+
+    Ok(())
+}
+
+async fn read_metadata() -> Result<Vec<EntryMetadata>, JsValue> {
+    let entries = run_request(
+        IDB_METADATA_STORE,
+        IdbTransactionMode::Readonly,
+        |object_store| object_store.get_all(),
+    )
+    .await?;
+
+    Ok(js_sys::Array::from(&entries)
+        .iter()
+        .filter_map(|entry| serde_wasm_bindgen::from_value(entry).ok())
+        .collect())
+}
+
+/// The metadata of every store lives in a single store, keyed by `[store, key]`.
+fn metadata_key(store: &str, key: &str) -> JsValue {
+    js_sys::Array::of2(&store.into(), &key.into()).into()
+}
+
+fn is_quota_exceeded(err: &JsValue) -> bool {
+    js_sys::Reflect::get(err, &"name".into())
+        .ok()
+        .and_then(|name| name.as_string())
+        .is_some_and(|name| name == "QuotaExceededError")
+}
+
+/// Runs a single request against the `store` object store in its own transaction.
+async fn run_request(
+    store: &str,
+    mode: IdbTransactionMode,
+    request: impl FnOnce(&IdbObjectStore) -> Result<IdbRequest, JsValue>,
+) -> Result<JsValue, JsValue> {
+    let db = open_database(store).await?;
+    let result = run_request_in(&db, store, mode, request).await;
+
+    db.close();
+    result
+}
+
+/// Runs a single request against the `store` object store of the open database, in its own transaction.
+async fn run_request_in(
+    db: &IdbDatabase,
+    store: &str,
+    mode: IdbTransactionMode,
+    request: impl FnOnce(&IdbObjectStore) -> Result<IdbRequest, JsValue>,
+) -> Result<JsValue, JsValue> {
+    match db
+        .transaction_with_str_and_mode(store, mode)
+        .and_then(|transaction| transaction.object_store(store))
+        .and_then(|object_store| request(&object_store))
+    {
+        Ok(request) => await_request(&request).await,
+        Err(err) => Err(err),
+    }
+}
+
+/// Scopes the key to the `cacheVersion` of the init config, so that entries written under another version are
+/// never read back. Without a version the key is used as is.
+fn versioned_key(key: &str) -> String {
+    let version = InMemoryCache::get_cache_version();
+    if version.is_empty() {
+        return key.to_string();
+    }
+
+    format!("{}:{}", version, key)
+}
+
+/// The key as given to `idb_put`, `None` when it was written under another `cacheVersion`.
+fn unversioned_key(key: &str, version: &str) -> Option<String> {
+    if version.is_empty() {
+        return Some(key.to_string());
+    }
+
+    key.strip_prefix(&format!("{}:", version))
+        .map(str::to_string)
+}
+
+/// Opens the database for the key-value calls, `None` when IndexedDB is unavailable: it is missing or fails to open
+/// in some private browsing modes or when disabled, and some browsers open it but fail every transaction. The calls
+/// then fall back to values kept in memory, with a single warning. With `create` the `store` object store is created
+/// when missing, otherwise its absence is an error.
+async fn open_key_value_database(
+    store: &str,
+    create: bool,
+) -> Result<Option<IdbDatabase>, JsValue> {
+    let unavailable = |err: JsValue| {
+        InMemoryCache::warn_idb_unavailable(&err);
+        Ok(None)
+    };
+
+    let opened = match idb_factory().and_then(|factory| factory.open(IDB_DATABASE_NAME)) {
+        Ok(request) => await_request(&request).await,
+        Err(err) => Err(err),
+    };
+    let db = match opened.and_then(|db| db.dyn_into::<IdbDatabase>()) {
+        Ok(db) => db,
+        Err(err) => return unavailable(err),
+    };
+
+    let db = if db.object_store_names().contains(store) {
+        db
+    } else if create {
+        db.close();
+        match open_database(store).await {
+            Ok(db) => db,
+            Err(err) => return unavailable(err),
+        }
+    } else {
+        db.close();
+        return Err(JsValue::from_str(&format!(
+            "The {} object store does not exist",
+            store
+        )));
+    };
+
+    // the transaction is never used, it commits on its own
+    if let Err(err) = db.transaction_with_str(store) {
+        db.close();
+        return unavailable(err);
+    }
+
+    Ok(Some(db))
+}
+
+fn idb_factory() -> Result<IdbFactory, JsValue> {
+    // `indexedDB` is read from the global scope so that it works in workers as well as in windows
+    js_sys::Reflect::get(&js_sys::global(), &"indexedDB".into())?
+        .dyn_into::<IdbFactory>()
+        .map_err(|_| JsValue::from_str("IndexedDB is not available in this environment"))
+}
+
+/// Opens the database, bumping its version to create the object store in `onupgradeneeded` when it is missing.
+async fn open_database(store: &str) -> Result<IdbDatabase, JsValue> {
+    let factory = idb_factory()?;
+
+    let db = await_request(&factory.open(IDB_DATABASE_NAME)?)
+        .await?
+        .dyn_into::<IdbDatabase>()?;
+    if db.object_store_names().contains(store) {
+        return Ok(db);
+    }
+
+    // object stores can only be created during a version change
+    let version = db.version() as u32 + 1;
+    db.close();
+
+    let open_request = factory.open_with_u32(IDB_DATABASE_NAME, version)?;
+    let upgrade_request = open_request.clone();
+    let store_name = store.to_string();
+    let on_upgrade_needed = Closure::once_into_js(move || {
+        if let Ok(db) = upgrade_request
+            .result()
+            .and_then(|db| db.dyn_into::<IdbDatabase>())
+        {
+            if !db.object_store_names().contains(&store_name) {
+                _ = db.create_object_store(&store_name);
+            }
+        }
+    });
+    open_request.set_onupgradeneeded(Some(on_upgrade_needed.unchecked_ref()));
+
+    await_request(&open_request)
+        .await?
+        .dyn_into::<IdbDatabase>()
+}
+
+/// Resolves with the result of the request once it succeeds, or rejects with its `DOMException`.
+async fn await_request(request: &IdbRequest) -> Result<JsValue, JsValue> {
+    let promise =
+        js_sys::Promise::new(&mut |resolve: js_sys::Function, reject: js_sys::Function| {
+            let success_request = request.clone();
+            let on_success = Closure::once_into_js(move || {
+                let result = success_request.result().unwrap_or(JsValue::UNDEFINED);
+                _ = resolve.call1(&JsValue::UNDEFINED, &result);
+            });
+
+            let error_request = request.clone();
+            let on_error = Closure::once_into_js(move || {
+                let error = error_request
+                    .error()
+                    .ok()
+                    .flatten()
+                    .map(JsValue::from)
+                    .unwrap_or_else(|| JsValue::from_str("IndexedDB request failed"));
+                _ = reject.call1(&JsValue::UNDEFINED, &error);
+            });
+
+            request.set_onsuccess(Some(on_success.unchecked_ref()));
+            request.set_onerror(Some(on_error.unchecked_ref()));
+        });
+
+    wasm_bindgen_futures::JsFuture::from(promise).await
+}
+
+/// Walks the key cursor of the request to its end and resolves with every key it went through.
+async fn collect_cursor_keys(request: &IdbRequest) -> Result<js_sys::Array, JsValue> {
+    let keys = js_sys::Array::new();
+    // kept alive until the cursor is done, it runs once per key
+    let mut on_success = None;
+    let promise =
+        js_sys::Promise::new(&mut |resolve: js_sys::Function, reject: js_sys::Function| {
+            let cursor_request = request.clone();
+            let collected = keys.clone();
+            let cursor_reject = reject.clone();
+            let closure = Closure::<dyn FnMut()>::new(move || {
+                // the result is `null` once the cursor went past the last key
+                match cursor_request
+                    .result()
+                    .ok()
+                    .and_then(|cursor| cursor.dyn_into::<IdbCursor>().ok())
+                {
+                    Some(cursor) => {
+                        collected.push(&cursor.key().unwrap_or(JsValue::UNDEFINED));
+                        if let Err(err) = cursor.continue_() {
+                            _ = cursor_reject.call1(&JsValue::UNDEFINED, &err);
+                        }
+                    }
+                    None => {
+                        _ = resolve.call0(&JsValue::UNDEFINED);
+                    }
+                }
+            });
+
+            let error_request = request.clone();
+            let on_error = Closure::once_into_js(move || {
+                let error = error_request
+                    .error()
+                    .ok()
+                    .flatten()
+                    .map(JsValue::from)
+                    .unwrap_or_else(|| JsValue::from_str("IndexedDB request failed"));
+                _ = reject.call1(&JsValue::UNDEFINED, &error);
+            });
+
+            request.set_onsuccess(Some(closure.as_ref().unchecked_ref()));
+            request.set_onerror(Some(on_error.unchecked_ref()));
+            on_success = Some(closure);
+        });
+
+    let result = wasm_bindgen_futures::JsFuture::from(promise).await;
+    request.set_onsuccess(None);
+    drop(on_success);
+
+    result.map(|_| keys)
+}
@@ -1,27 +1,33 @@
 use std::fmt::Debug;
 
+use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use wasm_bindgen::{JsValue, UnwrapThrowExt, prelude::wasm_bindgen};
-use web_sys::console;
+use wasm_bindgen::{JsCast, JsValue, UnwrapThrowExt, prelude::wasm_bindgen};
 
 use ntor::client::NTorClient;
-use ntor::common::{InitSessionResponse, NTorCertificate, NTorParty};
+use ntor::common::{EncryptedMessage, InitSessionResponse, NTorCertificate, NTorParty};
 
-use crate::constants::{INIT_TUNNEL_RETRY_ATTEMPTS, INIT_TUNNEL_RETRY_SLEEP_DELAY};
+use crate::constants::{INIT_TUNNEL_RETRY_BASE_DELAY, INIT_TUNNEL_RETRY_MAX_DELAY};
+use crate::queue;
 use crate::storage::InMemoryCache;
 use crate::types::{
+    config::InitConfig,
+    error::L8Error,
     http_caller::{ActualHttpCaller, HttpCaller, HttpCallerResponse},
-    network_state::NetworkStateOpen,
+    log_level::LogLevel,
+    network_state::{NetworkStateErrored, NetworkStateOpen},
     service_provider::ServiceProvider,
 };
-use crate::utils;
+use crate::utils::{self, CompressorVariant};
 
 #[derive(Clone)]
 pub struct InitTunnelResult {
     pub(crate) client: NTorClient,
     pub(crate) int_rp_jwt: String,
     pub(crate) int_fp_jwt: String,
+    /// The response encodings negotiated with the proxy during the handshake.
+    pub(crate) accepted_encodings: Vec<CompressorVariant>,
 }
 
 impl InitTunnelResult {
@@ -30,13 +36,68 @@ impl InitTunnelResult {
             client: NTorClient::new(),
             int_rp_jwt: String::new(),
             int_fp_jwt: String::new(),
+            accepted_encodings: CompressorVariant::ALL.to_vec(),
         }
     }
 
+    /// The response encodings the interceptor decodes for this tunnel.
+    pub fn accepted_encodings(&self) -> &[CompressorVariant] {
+        &self.accepted_encodings
+    }
+
+    /// A short, non-secret fingerprint of the NTor shared secret (see `getSharedSecretFingerprint`), `None` before
+    /// the handshake completed.
+    pub fn shared_secret_fingerprint(&self) -> Option<String> {
+        self.client
+            .get_shared_secret()
+            .map(|secret| utils::secret_fingerprint(&secret))
+    }
+
     fn generate_ntor_client_public_key(&mut self) -> Vec<u8> {
         let init_session_msg = self.client.initialise_session();
         init_session_msg.public_key()
     }
+
+    /// Encrypts the data with the session key and frames it as a bincode encoded `EncryptedMessage`.
+    pub fn ntor_encrypt(&self, data: Vec<u8>) -> Result<Vec<u8>, JsValue> {
+        let (nonce, encrypted) = self
+            .client
+            .wasm_encrypt(data)
+            .map_err(|e| L8Error::CryptoError(format!("Failed to encrypt data: {}", e)))?;
+
+        let nonce = TryInto::<[u8; 12]>::try_into(nonce).map_err(|_e| {
+            L8Error::CryptoError("Failed to convert nonce to array of 12 bytes".to_string())
+        })?;
+
+        let msg = bincode::encode_to_vec(
+            &EncryptedMessage {
+                nonce,
+                data: encrypted,
+            },
+            bincode::config::standard(),
+        )
+        .map_err(|e| {
+            L8Error::CryptoError(format!("Failed to serialize encrypted message: {}", e))
+        })?;
+
+        Ok(msg)
+    }
+
+    /// Decodes a bincode encoded `EncryptedMessage` and decrypts it with the session key.
+    pub fn ntor_decrypt(&self, data: &Bytes) -> Result<Vec<u8>, JsValue> {
+        let encrypted_data =
+            bincode::decode_from_slice::<EncryptedMessage, _>(data, bincode::config::standard())
+                .map_err(|e| {
+                    L8Error::CryptoError(format!("Failed to deserialize encrypted message: {}", e))
+                })?;
+
+        let decrypted_response = self
+            .client
+            .wasm_decrypt(encrypted_data.0.nonce.to_vec(), encrypted_data.0.data)
+            .map_err(|e| L8Error::CryptoError(format!("Failed to decrypt data: {}", e)))?;
+
+        Ok(decrypted_response)
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -50,6 +111,9 @@ pub struct InitTunnelResponse {
     pub server_id: String,
     #[serde(rename = "public_key")]
     pub static_public_key: Vec<u8>,
+    /// The subset of the advertised `x-l8-accept-encoding` the proxy agreed on, absent if it ignores the header.
+    #[serde(default)]
+    pub accept_encoding: Option<String>,
 }
 
 impl InitTunnelResponse {
@@ -78,35 +142,42 @@ impl Debug for InitTunnelResult {
 /// # Arguments
 /// * `backend_url` - The `init-tunnel` endpoint of the target server (forward-proxy) includes reverse-proxy's url as a param
 /// (eg. https://fp.layer8.net/init-tunnel?backend_url=https://backendwithreverseproxy.layer8.net)
+/// * `http_client` - The `reqwest::Client` owned by the provider; it is reused across retries to keep the connection pool warm.
 /// * `http_caller` - An implementation of the `HttpCaller` trait to send HTTP requests (either real http call or mock test).
 /// # Returns
 /// * `InitTunnelResult` if success - Contains the NTor Client and JWT tokens for further communication.
 /// * Error if any step fails during the process:
-///     - Sending request to backend failed (after INIT_TUNNEL_RETRY_ATTEMPTS retries)
+///     - Sending request to backend failed (after `initRetries` attempts, see `InitConfig`)
 ///     - Processing the response failed
 ///     - NTor handshake failed
+///     - The proxy returned empty JWTs, the provider is then marked `ERRORED` instead of `OPEN`
+///     - The proxy's static public key doesn't match the one pinned for the `backend_url` provider (`CertPinMismatch`)
 pub async fn init_tunnel(
     backend_url: String,
+    http_client: &reqwest::Client,
     http_caller: impl HttpCaller,
 ) -> Result<InitTunnelResult, JsValue> {
-    let dev_flag = InMemoryCache::get_dev_flag();
-
     // 1. Initialize NTor Client message
     let mut init_tunnel_result = InitTunnelResult::new();
     let request_body = json!({
         "public_key": init_tunnel_result.generate_ntor_client_public_key(),
     });
 
-    // 2. Try to send the request to the backend up to INIT_TUNNEL_RETRY_ATTEMPTS times
+    // 2. Try to send the request to the backend up to `initRetries` times
+    let max_attempts = InMemoryCache::get_init_retries();
     let mut retry_attempt = 0;
     let response: HttpCallerResponse;
     loop {
         retry_attempt += 1;
 
-        let req_builder = reqwest::Client::new()
+        let req_builder = http_client
             .post(backend_url.clone())
             .header("Content-Length", "application/json")
             .header("Retry-count", retry_attempt)
+            .header(
+                "x-l8-accept-encoding",
+                CompressorVariant::accept_encoding(&CompressorVariant::ALL),
+            )
             .body(request_body.to_string());
 
         match http_caller.clone().send(req_builder).await {
@@ -116,55 +187,91 @@ pub async fn init_tunnel(
             }
             // If it fails, log the error and retry after a short delay
             Err(err) => {
-                if dev_flag {
-                    console::error_1(
-                        &format!("Request attempt {} failed: {}", retry_attempt, err).into(),
-                    );
-                }
-
-                if retry_attempt >= INIT_TUNNEL_RETRY_ATTEMPTS {
-                    console::error_1(
+                InMemoryCache::log(
+                    LogLevel::Warn,
+                    &format!("Request attempt {} failed: {}", retry_attempt, err).into(),
+                );
+
+                if retry_attempt >= max_attempts {
+                    InMemoryCache::log(
+                        LogLevel::Error,
                         &format!("Init-tunnel failed after {} attempts", retry_attempt).into(),
                     );
 
-                    return Err(JsValue::from_str(&format!(
+                    return Err(L8Error::NetworkError(format!(
                         "Failed to initialize tunnel after {} attempts: {}",
                         retry_attempt, err
-                    )));
+                    ))
+                    .into());
                 }
 
-                // Wait for a short period before retrying
-                utils::sleep(INIT_TUNNEL_RETRY_SLEEP_DELAY).await;
+                // Back off exponentially (with jitter) before retrying
+                utils::sleep(utils::backoff_delay(
+                    retry_attempt,
+                    INIT_TUNNEL_RETRY_BASE_DELAY,
+                    INIT_TUNNEL_RETRY_MAX_DELAY,
+                ))
+                .await;
             }
         };
     }
 
     // 3. Parse the response
+    let status = response.status().as_u16();
     let response_body = match response.bytes().await {
-        Ok(bytes) => serde_json::from_slice::<InitTunnelResponse>(&bytes)
-            .expect_throw("Failed to deserialize response body to InitTunnelResponse"),
-        Err(err) => {
-            if dev_flag {
-                console::error_1(&format!("Cannot read response body: {}", err).into());
+        Ok(bytes) => serde_json::from_slice::<InitTunnelResponse>(&bytes).map_err(|e| {
+            L8Error::ProxyError {
+                status,
+                message: format!(
+                    "Failed to deserialize response body to InitTunnelResponse: {}",
+                    e
+                ),
             }
-
-            return Err(JsValue::from_str(&format!(
-                "Cannot read response body: {:?}",
-                err
-            )));
+        })?,
+        Err(err) => {
+            InMemoryCache::log(
+                LogLevel::Error,
+                &format!("Cannot read response body: {}", err).into(),
+            );
+
+            return Err(
+                L8Error::NetworkError(format!("Cannot read response body: {:?}", err)).into(),
+            );
         }
     };
 
-    // 4. Complete NTor handshake
+    // every request through the proxy is authorized by these tokens, a tunnel without them is unusable
+    if response_body.int_rp_jwt.is_empty() || response_body.int_fp_jwt.is_empty() {
+        return Err(L8Error::ProxyError {
+            status,
+            message: "The init-tunnel handshake succeeded but returned an empty JWT".to_string(),
+        }
+        .into());
+    }
+
+    // 4. Check the server's static key against the pinned one, before trusting it for the handshake
+    if let Some(pinned_key) = pinned_server_key(&backend_url) {
+        if pinned_key != response_body.static_public_key {
+            return Err(L8Error::CertPinMismatch(format!(
+                "The static public key presented by the proxy for {} doesn't match the pinned one",
+                backend_url
+            ))
+            .into());
+        }
+    }
+
+    // 5. Complete NTor handshake
     if !response_body.compute_ntor_handshake(&mut init_tunnel_result.client) {
-        return Err(JsValue::from_str("Failed to create nTor Client"));
+        return Err(L8Error::CryptoError("Failed to create nTor Client".to_string()).into());
     };
 
-    if dev_flag {
-        console::log_1(
+    // the logs may leave the devtools through `setLogger`, only the fingerprint of the secret is logged
+    if InMemoryCache::get_dev_flag() {
+        InMemoryCache::log(
+            LogLevel::Debug,
             &format!(
-                "NTor shared secret: {:?}",
-                init_tunnel_result.client.get_shared_secret().expect_throw(
+                "NTor shared secret fingerprint: {}",
+                init_tunnel_result.shared_secret_fingerprint().expect_throw(
                     "Shared secret should be available after successful tunnel initialization"
                 )
             )
@@ -172,54 +279,359 @@ pub async fn init_tunnel(
         );
     }
 
+    // a proxy unaware of the negotiation leaves every supported variant enabled
+    if let Some(accept_encoding) = &response_body.accept_encoding {
+        init_tunnel_result.accepted_encodings = accept_encoding
+            .split(',')
+            .filter_map(CompressorVariant::from_content_encoding)
+            .collect();
+    }
+
     init_tunnel_result.int_rp_jwt = response_body.int_rp_jwt;
     init_tunnel_result.int_fp_jwt = response_body.int_fp_jwt;
 
     Ok(init_tunnel_result)
 }
 
+/// A tunnel established with `ntorHandshake`, for embedders carrying their own protocol (e.g. WebSocket framing)
+/// over the NTor session instead of `fetch`. Its messages are framed like the ones of `fetch` requests.
+#[wasm_bindgen(js_name = "NTorTunnel")]
+pub struct NTorTunnel {
+    inner: InitTunnelResult,
+}
+
+#[wasm_bindgen(js_class = "NTorTunnel")]
+impl NTorTunnel {
+    #[wasm_bindgen(getter, js_name = "intRpJwt")]
+    pub fn int_rp_jwt(&self) -> String {
+        self.inner.int_rp_jwt.clone()
+    }
+
+    #[wasm_bindgen(getter, js_name = "intFpJwt")]
+    pub fn int_fp_jwt(&self) -> String {
+        self.inner.int_fp_jwt.clone()
+    }
+
+    pub fn encrypt(&self, data: Vec<u8>) -> Result<Vec<u8>, JsValue> {
+        self.inner.ntor_encrypt(data)
+    }
+
+    pub fn decrypt(&self, data: Vec<u8>) -> Result<Vec<u8>, JsValue> {
+        self.inner.ntor_decrypt(&Bytes::from(data))
+    }
+}
+
+impl From<InitTunnelResult> for NTorTunnel {
+    fn from(inner: InitTunnelResult) -> Self {
+        NTorTunnel { inner }
+    }
+}
+
+/// Returns a short fingerprint of the NTor shared secret of the provider's tunnel, for operators to check that a
+/// client and the proxy negotiated the same session. It is a truncated SHA-256 of the secret, which is never exposed.
+/// `None` unless the tunnel of the provider is `OPEN`.
+#[wasm_bindgen(js_name = "getSharedSecretFingerprint")]
+pub fn shared_secret_fingerprint(provider_url: String) -> Option<String> {
+    let base_url = utils::get_base_url(&provider_url).ok()?;
+    InMemoryCache::get_open_network_state(&base_url)?
+        .init_tunnel_result
+        .shared_secret_fingerprint()
+}
+
+/// Returns the schemes of the provider's tunnel as `{ proxy, backend }`, e.g. `{ proxy: "https", backend: "http" }`
+/// behind a proxy terminating TLS. `undefined` unless the tunnel of the provider is `OPEN`.
+#[wasm_bindgen(js_name = "getProviderSchemes")]
+pub fn provider_schemes(provider_url: String) -> JsValue {
+    utils::get_base_url(&provider_url)
+        .ok()
+        .and_then(|base_url| InMemoryCache::get_provider_schemes(&base_url))
+        .and_then(|schemes| serde_wasm_bindgen::to_value(&schemes).ok())
+        .unwrap_or(JsValue::UNDEFINED)
+}
+
+/// Subscribes `cb` to the tunnel state transitions. It is called as `(provider, oldState, newState)` whenever the
+/// tunnel of a provider moves between `CONNECTING`, `OPEN` and `ERRORED`, `oldState` being `null` for the first
+/// state of the provider. Every subscriber is called, in subscription order, even when one of them throws.
+#[wasm_bindgen(js_name = "onTunnelStateChange")]
+pub fn on_tunnel_state_change(cb: js_sys::Function) {
+    InMemoryCache::add_tunnel_state_listener(cb);
+}
+
+/// Performs the NTor key exchange with the `init-tunnel` endpoint (see `init_tunnel`) and hands the session over
+/// instead of registering it as a provider, `fetch` never uses it.
+#[wasm_bindgen(js_name = "ntorHandshake")]
+pub async fn ntor_handshake(backend_url: String) -> Result<NTorTunnel, JsValue> {
+    let http_client = reqwest::Client::new();
+    init_tunnel(backend_url, &http_client, ActualHttpCaller)
+        .await
+        .map(NTorTunnel::from)
+}
+
+/// Runs the `init_tunnel` handshake for the provider through each forward proxy in turn, until one of them completes
+/// it. Returns the result along with the url of that proxy, or the error of the last proxy tried.
+pub async fn init_tunnel_with_failover(
+    forward_proxy_urls: &[String],
+    provider_base_url: &str,
+    http_client: &reqwest::Client,
+    http_caller: impl HttpCaller,
+) -> Result<(InitTunnelResult, String), JsValue> {
+    let mut last_error: JsValue =
+        L8Error::NetworkError("No forward proxy to initialize the tunnel through".to_string())
+            .into();
+
+    for forward_proxy_url in forward_proxy_urls {
+        let backend_url = format!(
+            "{}/init-tunnel?backend_url={}",
+            forward_proxy_url, provider_base_url
+        );
+
+        match init_tunnel(backend_url, http_client, http_caller.clone()).await {
+            Ok(result) => return Ok((result, forward_proxy_url.clone())),
+            Err(err) => {
+                InMemoryCache::log(
+                    LogLevel::Warn,
+                    &format!(
+                        "Handshake for {} through {} failed, trying the next forward proxy",
+                        provider_base_url, forward_proxy_url
+                    )
+                    .into(),
+                );
+                last_error = err;
+            }
+        }
+    }
+
+    Err(last_error)
+}
+
+/// Returns the key pinned for the provider the `init-tunnel` url is for (its `backend_url` param), if any.
+fn pinned_server_key(init_tunnel_url: &str) -> Option<Vec<u8>> {
+    let url = url::Url::parse(init_tunnel_url).ok()?;
+    let (_, provider_url) = url.query_pairs().find(|(name, _)| name == "backend_url")?;
+
+    InMemoryCache::get_pinned_server_key(&utils::get_base_url(&provider_url).ok()?)
+}
+
+/// Orders the service providers so that the ones with the highest priority are handshaked first.
+/// Providers sharing the same priority keep the order they were given in.
+pub fn sort_by_priority(service_providers: &mut [ServiceProvider]) {
+    service_providers.sort_by_key(|provider| std::cmp::Reverse(provider.priority()));
+}
+
 /// This function initializes the encrypted tunnel for the given service providers using a background process, which updates
 /// the `NETWORK_STATE` global static.
+///
+/// The background tasks are scheduled in descending `priority` order (see `ServiceProvider` options), so critical
+/// providers start their handshake first.
+///
+/// The optional `config` object is described by `InitConfig`. Prefer `initEncryptedTunnelWithConfig`, which takes
+/// everything, `devFlag` included, as a single object.
 #[wasm_bindgen(js_name = "initEncryptedTunnel")]
 pub fn init_encrypted_tunnels(
     forward_proxy_url: String,
     service_providers: Vec<ServiceProvider>,
     dev_flag: Option<bool>,
+    config: Option<js_sys::Object>,
 ) -> Result<(), JsValue> {
-    let dev_flag = InMemoryCache::set_dev_flag(dev_flag);
+    let mut config = InitConfig::from_js(config)?;
+    // the positional flag predates the config object, which wins when both are given
+    config.dev_flag = config.dev_flag.or(dev_flag);
 
-    for service_provider in service_providers {
-        // update the urls as connecting before scheduling the background task to initialize the tunnel
-        InMemoryCache::set_connecting_network_state(&service_provider.url);
-
-        let base_url = utils::get_base_url(&service_provider.url)?;
-        let backend_url = format!("{}/init-tunnel?backend_url={}", forward_proxy_url, base_url);
-        let forward_proxy_url = forward_proxy_url.clone();
-
-        // schedule the background task to initialize the tunnel
-        wasm_bindgen_futures::spawn_local(async move {
-            match init_tunnel(backend_url, ActualHttpCaller).await {
-                Ok(val) => {
-                    if dev_flag {
-                        console::log_1(
-                            &format!("Tunnel initialized for {}", service_provider.url).into(),
-                        );
-                    }
-
-                    let state = NetworkStateOpen {
-                        http_client: reqwest::Client::new(),
-                        init_tunnel_result: val,
-                        forward_proxy_url: forward_proxy_url.clone(),
-                    };
-
-                    InMemoryCache::set_open_network_state(&base_url, state);
-                }
-                Err(err) => {
-                    InMemoryCache::set_errored_network_state(&base_url, err);
-                }
-            }
-        });
+    init_with_config(forward_proxy_url, service_providers, config)
+}
+
+/// Like `initEncryptedTunnel`, from a single object holding the `forwardProxyUrl`, the `serviceProviders` (as
+/// `{ url, options }` objects) and any `InitConfig` field, e.g.
+/// `{ forwardProxyUrl, serviceProviders: [{ url: "https://provider.com" }], devFlag: true }`.
+#[wasm_bindgen(js_name = "initEncryptedTunnelWithConfig")]
+pub fn init_encrypted_tunnels_with_config(config: js_sys::Object) -> Result<(), JsValue> {
+    let invalid = |message: &str| JsValue::from_str(&format!("Invalid init config: {}", message));
+
+    let forward_proxy_url = js_sys::Reflect::get(&config, &"forwardProxyUrl".into())?
+        .as_string()
+        .ok_or_else(|| invalid("`forwardProxyUrl` must be a string"))?;
+
+    let service_providers = js_sys::Reflect::get(&config, &"serviceProviders".into())?;
+    let service_providers = if service_providers.is_undefined() {
+        Vec::new()
+    } else if js_sys::Array::is_array(&service_providers) {
+        js_sys::Array::from(&service_providers)
+            .iter()
+            .map(|provider| {
+                let url = js_sys::Reflect::get(&provider, &"url".into())?
+                    .as_string()
+                    .ok_or_else(|| invalid("every service provider needs a string `url`"))?;
+                let options = js_sys::Reflect::get(&provider, &"options".into())?
+                    .dyn_into::<js_sys::Object>()
+                    .ok();
+
+                Ok(ServiceProvider::new(url, options))
+            })
+            .collect::<Result<Vec<_>, JsValue>>()?
+    } else {
+        return Err(invalid("`serviceProviders` must be an array"));
+    };
+
+    init_with_config(
+        forward_proxy_url,
+        service_providers,
+        InitConfig::from_js(Some(config))?,
+    )
+}
+
+fn init_with_config(
+    forward_proxy_url: String,
+    mut service_providers: Vec<ServiceProvider>,
+    mut config: InitConfig,
+) -> Result<(), JsValue> {
+    InMemoryCache::set_log_level(
+        config
+            .log_level
+            .unwrap_or_else(|| LogLevel::from_dev_flag(config.dev_flag)),
+    );
+
+    // the browser only ever talks to the forward proxies, the backends' schemes are the proxy's concern
+    utils::check_mixed_content(&forward_proxy_url)?;
+    for fallback_url in &config.fallback_forward_proxy_urls {
+        utils::check_mixed_content(fallback_url)?;
     }
 
+    config.forward_proxy_url = forward_proxy_url.clone();
+    InMemoryCache::set_config(config);
+
+    sort_by_priority(&mut service_providers);
+    schedule_service_providers(&forward_proxy_url, &service_providers)?;
+
     Ok(())
 }
+
+/// Schedules the handshake of every provider that doesn't already have a usable tunnel and returns the network
+/// state keys (the base url, with the `sessionKey` if any) of the providers that were scheduled.
+///
+/// Providers that are `OPEN` or `CONNECTING` (e.g. shared with an earlier `initEncryptedTunnel` call, or listed twice)
+/// are left untouched, so overlapping initializations handshake each provider once.
+pub fn schedule_service_providers(
+    forward_proxy_url: &str,
+    service_providers: &[ServiceProvider],
+) -> Result<Vec<String>, JsValue> {
+    let mut scheduled = Vec::new();
+    for service_provider in service_providers {
+        let base_url = utils::session_cache_key(
+            &utils::get_base_url(&service_provider.url)?,
+            service_provider.session_key().as_deref(),
+        );
+
+        if InMemoryCache::is_open_or_connecting(&base_url) {
+            InMemoryCache::log(
+                LogLevel::Debug,
+                &format!("Tunnel for {} is already open or connecting", base_url).into(),
+            );
+            continue;
+        }
+
+        // one client per provider, shared by the handshake and all subsequent requests
+        if schedule_init_tunnel(
+            base_url.clone(),
+            forward_proxy_url.to_string(),
+            reqwest::Client::new(),
+        ) {
+            scheduled.push(base_url);
+        }
+    }
+
+    Ok(scheduled)
+}
+
+/// Retries the tunnel initialization of a provider whose previous attempt failed. The provider is flipped
+/// back to `CONNECTING` and a new handshake is scheduled in the background.
+///
+/// Returns `false` when the provider is not in the errored state, in which case nothing is done.
+///
+/// `session_key` selects the session of providers registered with a `sessionKey`.
+#[wasm_bindgen(js_name = "retryEncryptedTunnel")]
+pub fn retry_encrypted_tunnel(
+    provider_url: String,
+    session_key: Option<String>,
+) -> Result<bool, JsValue> {
+    let base_url =
+        utils::session_cache_key(&utils::get_base_url(&provider_url)?, session_key.as_deref());
+    match InMemoryCache::get_errored_network_state(&base_url) {
+        Some((forward_proxy_url, http_client)) => {
+            schedule_init_tunnel(base_url, forward_proxy_url, http_client);
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Marks the provider as `CONNECTING` and schedules the background task running the handshake, which
+/// settles the provider's network state to either `OPEN` or `ERRORED`. `base_url` is the network state key,
+/// which may carry a session (see `utils::session_cache_key`).
+///
+/// Returns `false` when a handshake for the provider is already in flight.
+pub(crate) fn schedule_init_tunnel(
+    base_url: String,
+    forward_proxy_url: String,
+    http_client: reqwest::Client,
+) -> bool {
+    schedule_init_tunnel_with(base_url, forward_proxy_url, http_client, ActualHttpCaller)
+}
+
+/// `schedule_init_tunnel` running the handshake through the given `HttpCaller`.
+pub fn schedule_init_tunnel_with(
+    base_url: String,
+    forward_proxy_url: String,
+    http_client: reqwest::Client,
+    http_caller: impl HttpCaller + 'static,
+) -> bool {
+    // a handshake for this provider is already running, it will settle the network state
+    if !InMemoryCache::try_begin_init(&base_url) {
+        return false;
+    }
+
+    // update the url as connecting before scheduling the background task to initialize the tunnel
+    InMemoryCache::set_connecting_network_state(&base_url);
+
+    let forward_proxy_urls = InMemoryCache::get_forward_proxy_urls(&forward_proxy_url, false);
+    wasm_bindgen_futures::spawn_local(async move {
+        let result = init_tunnel_with_failover(
+            &forward_proxy_urls,
+            utils::session_base_url(&base_url),
+            &http_client,
+            http_caller.clone(),
+        )
+        .await;
+        InMemoryCache::end_init(&base_url);
+
+        match result {
+            Ok((val, forward_proxy_url)) => {
+                InMemoryCache::log(
+                    LogLevel::Info,
+                    &format!("Tunnel initialized for {}", base_url).into(),
+                );
+
+                let state = NetworkStateOpen {
+                    http_client,
+                    init_tunnel_result: val,
+                    forward_proxy_url,
+                };
+
+                InMemoryCache::set_open_network_state(&base_url, state);
+
+                queue::flush_on_open(&base_url, http_caller).await;
+            }
+            Err(err) => {
+                let state = NetworkStateErrored {
+                    error: err,
+                    forward_proxy_url,
+                    http_client,
+                    errored_at: js_sys::Date::now(),
+                };
+
+                InMemoryCache::set_errored_network_state(&base_url, state);
+            }
+        }
+    });
+
+    true
+}
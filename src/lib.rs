@@ -1,6 +1,8 @@
 pub(crate) mod constants;
 pub mod fetch;
+pub mod indexeddb;
 pub mod init_tunnel;
+pub mod queue;
 mod storage;
 pub mod types;
 pub mod utils;
@@ -0,0 +1,269 @@
+use crate::constants::{IDB_REQUEST_QUEUE_STORE, IDEMPOTENT_HTTP_METHODS, REQUEST_QUEUE_CAPACITY};
+use crate::storage::InMemoryCache;
+use crate::types::{
+    error::L8Error,
+    http_caller::{ActualHttpCaller, HttpCaller},
+    log_level::LogLevel,
+    request::{L8RequestObject, abort_error},
+};
+use crate::{fetch, indexeddb, utils};
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use wasm_bindgen::prelude::*;
+use web_sys::RequestInit;
+
+/// A request issued while its provider was unreachable, kept in IndexedDB until the provider's queue is flushed.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct QueuedRequest {
+    /// The network state key of the provider.
+    provider: String,
+    url: String,
+    method: String,
+    /// The headers without `Cookie`, the document cookies are attached again on replay.
+    headers: HashMap<String, serde_json::Value>,
+    body: Vec<u8>,
+    session_key: Option<String>,
+    /// The `credentials` option, missing from the requests queued before it was persisted.
+    #[serde(default)]
+    credentials: Option<String>,
+}
+
+impl QueuedRequest {
+    /// The fetch options replaying the request. A replay is never queued again, it fails like any other request.
+    fn request_init(&self) -> Result<RequestInit, JsValue> {
+        let init = RequestInit::new();
+        init.set_method(&self.method);
+        init.set_headers(&utils::hashmap_to_js_headers(&self.headers)?);
+        if !self.body.is_empty() {
+            init.set_body(&js_sys::Uint8Array::from(self.body.as_slice()));
+        }
+
+        js_sys::Reflect::set(&init, &"queueOffline".into(), &false.into())?;
+        if let Some(credentials) = &self.credentials {
+            js_sys::Reflect::set(&init, &"credentials".into(), &credentials.into())?;
+        }
+        if let Some(session_key) = &self.session_key {
+            js_sys::Reflect::set(&init, &"sessionKey".into(), &session_key.into())?;
+        }
+
+        Ok(init)
+    }
+}
+
+/// Whether the request, which could not be sent through the tunnel of `network_state_key`, should wait in the
+/// offline queue: the `queueOffline` init config must be enabled, the provider `ERRORED` or the browser offline, and
+/// the request idempotent unless its `queueOffline` option says otherwise.
+pub(crate) fn should_queue(request: &L8RequestObject, network_state_key: &str) -> bool {
+    InMemoryCache::get_queue_offline()
+        && request
+            .queue_offline
+            .unwrap_or_else(|| IDEMPOTENT_HTTP_METHODS.contains(&request.method.as_str()))
+        && (InMemoryCache::get_errored_network_state(network_state_key).is_some()
+            || utils::is_offline())
+}
+
+/// Persists the request in the offline queue and waits for its replay (see `flushQueue`), resolving with the
+/// response of the replay. Fails right away with `error`, the reason the request could not be sent, when the queue
+/// already holds `REQUEST_QUEUE_CAPACITY` requests. The wait ends with an `AbortError` once the `signal` of the
+/// request is aborted, or with a `TimeoutError` after the `queueTimeoutMs` of the init config; the request is then
+/// dropped from the queue.
+///
+/// The request is persisted without its `Cookie` header, which holds the document cookies once `apply_credentials`
+/// ran; the replay attaches them again. Its other headers are persisted as they are.
+pub(crate) async fn enqueue(
+    network_state_key: &str,
+    url: &str,
+    request: &L8RequestObject,
+    error: JsValue,
+) -> Result<web_sys::Response, JsValue> {
+    // a queue that can't be written to fails the request with the reason it could not be sent
+    let unavailable = |err: JsValue, error: JsValue| {
+        InMemoryCache::log(
+            LogLevel::Warn,
+            &format!("Failed to queue {}: {:?}", url, err).into(),
+        );
+        Err(error)
+    };
+
+    let queued_count = match indexeddb::idb_get_all(IDB_REQUEST_QUEUE_STORE).await {
+        Ok(queued) => queued.length(),
+        Err(err) => return unavailable(err, error),
+    };
+    if queued_count >= REQUEST_QUEUE_CAPACITY {
+        InMemoryCache::log(
+            LogLevel::Warn,
+            &format!("The offline queue is full, {} is not queued", url).into(),
+        );
+        return Err(error);
+    }
+
+    let queued = QueuedRequest {
+        provider: network_state_key.to_string(),
+        url: url.to_string(),
+        method: request.method.clone(),
+        headers: request
+            .headers
+            .iter()
+            .filter(|(name, _)| !name.eq_ignore_ascii_case("cookie"))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect(),
+        body: request.body.clone(),
+        session_key: request.session_key.clone(),
+        credentials: Some(request.credentials.clone()),
+    };
+    // header values are JSON values, only the JSON compatible serializer turns maps into plain objects
+    let value = queued
+        .serialize(&serde_wasm_bindgen::Serializer::json_compatible())
+        .map_err(|e| {
+            JsValue::from(L8Error::InvalidRequest(format!(
+                "Failed to serialize the queued request: {}",
+                e
+            )))
+        })?;
+    let key = match indexeddb::idb_append(IDB_REQUEST_QUEUE_STORE, &value, REQUEST_QUEUE_CAPACITY)
+        .await
+    {
+        Ok(key) => key.as_f64().expect_throw("queue keys are timestamps"),
+        Err(err) => return unavailable(err, error),
+    };
+
+    // the executor runs synchronously, the waiter is registered before the promise is awaited
+    let replayed = js_sys::Promise::new(&mut |resolve, reject| {
+        InMemoryCache::add_queue_waiter(key, (resolve, reject));
+    });
+
+    if let Some(signal) = &request.signal {
+        let (signal, url) = (signal.clone(), url.to_string());
+        let on_abort = Closure::once_into_js({
+            let signal = signal.clone();
+            move || stop_waiting(key, abort_error(&signal, &url))
+        });
+        signal.add_event_listener_with_callback("abort", on_abort.unchecked_ref())?;
+    }
+
+    let on_timeout = Closure::once_into_js({
+        let url = url.to_string();
+        move || {
+            stop_waiting(
+                key,
+                L8Error::TimeoutError(format!("The queued request to {} timed out", url)).into(),
+            )
+        }
+    });
+    utils::set_timeout(
+        on_timeout.unchecked_ref(),
+        InMemoryCache::get_queue_timeout_ms() as i32,
+    );
+
+    InMemoryCache::log(
+        LogLevel::Info,
+        &format!(
+            "Queued {} {} until {} is reachable",
+            queued.method, url, network_state_key
+        )
+        .into(),
+    );
+
+    let response = wasm_bindgen_futures::JsFuture::from(replayed).await?;
+    Ok(response.unchecked_into())
+}
+
+/// Fails the `fetch` call waiting for the queued request `key` with `error` and drops the request from the queue,
+/// unless its replay already started.
+fn stop_waiting(key: f64, error: JsValue) {
+    let Some((_, reject)) = InMemoryCache::take_queue_waiter(key) else {
+        return;
+    };
+
+    _ = reject.call1(&JsValue::NULL, &error);
+    wasm_bindgen_futures::spawn_local(async move {
+        if let Err(err) = indexeddb::idb_remove(IDB_REQUEST_QUEUE_STORE, &key.into()).await {
+            InMemoryCache::log(LogLevel::Warn, &err);
+        }
+    });
+}
+
+/// Flushes the queue of the provider once its tunnel is `OPEN` again, when the `queueOffline` init config is enabled.
+pub(crate) async fn flush_on_open(network_state_key: &str, http_caller: impl HttpCaller + 'static) {
+    if !InMemoryCache::get_queue_offline() {
+        return;
+    }
+
+    if let Err(err) = flush_queue_with(network_state_key.to_string(), http_caller).await {
+        InMemoryCache::log(LogLevel::Warn, &err);
+    }
+}
+
+/// Replays, in order, the requests queued while the provider was unreachable, settling the `fetch` calls waiting for
+/// them, and resolves with the number of requests replayed successfully. The queues of every session of the provider
+/// are flushed. Replays failing again are not queued anew, their `fetch` calls fail with the error.
+///
+/// This runs on its own when `initEncryptedTunnel` reopens the provider, the `onQueueFlushed` callback of the init
+/// config is then called with the provider and the number of requests replayed successfully.
+#[wasm_bindgen(js_name = "flushQueue")]
+pub async fn flush_queue(provider: String) -> Result<u32, JsValue> {
+    flush_queue_with(provider, ActualHttpCaller).await
+}
+
+/// `flushQueue` replaying the requests through the given `HttpCaller`.
+pub async fn flush_queue_with(
+    provider: String,
+    http_caller: impl HttpCaller + 'static,
+) -> Result<u32, JsValue> {
+    let base_url = utils::get_base_url(&provider)?;
+    let mut replayed = 0;
+
+    for (key, value) in indexeddb::idb_get_all_entries(IDB_REQUEST_QUEUE_STORE).await? {
+        let queued = match serde_wasm_bindgen::from_value::<QueuedRequest>(value) {
+            Ok(queued) if utils::session_base_url(&queued.provider) == base_url => queued,
+            Ok(_) => continue,
+            Err(e) => {
+                InMemoryCache::log(
+                    LogLevel::Warn,
+                    &format!("Dropping an unreadable queued request: {}", e).into(),
+                );
+                indexeddb::idb_remove(IDB_REQUEST_QUEUE_STORE, &key).await?;
+                continue;
+            }
+        };
+
+        // claimed before the replay, a concurrent flush having claimed it first replays it instead
+        if indexeddb::idb_take(IDB_REQUEST_QUEUE_STORE, &key)
+            .await?
+            .is_none()
+        {
+            continue;
+        }
+        let result = fetch::fetch_with(
+            queued.url.clone().into(),
+            Some(queued.request_init()?),
+            http_caller.clone(),
+        )
+        .await;
+        if result.is_ok() {
+            replayed += 1;
+        }
+
+        // after a reload, an abort or a timeout nobody waits for the replay anymore
+        let waiter = key.as_f64().and_then(InMemoryCache::take_queue_waiter);
+        match (waiter, result) {
+            (Some((resolve, _)), Ok(response)) => {
+                _ = resolve.call1(&JsValue::NULL, &response);
+            }
+            (Some((_, reject)), Err(err)) => {
+                _ = reject.call1(&JsValue::NULL, &err);
+            }
+            (None, Ok(_)) => {}
+            (None, Err(err)) => InMemoryCache::log(LogLevel::Warn, &err),
+        }
+    }
+
+    if let Some(on_queue_flushed) = InMemoryCache::get_on_queue_flushed().filter(|_| replayed > 0) {
+        // a throwing callback must not fail the flush
+        _ = on_queue_flushed.call2(&JsValue::NULL, &base_url.into(), &replayed.into());
+    }
+
+    Ok(replayed)
+}
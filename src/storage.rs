@@ -1,9 +1,21 @@
-use crate::constants::FETCH_RETRY_SLEEP_DELAY;
-use crate::types::network_state::{NetworkState, NetworkStateOpen};
-use crate::utils;
+use crate::constants::{FETCH_RETRY_SLEEP_DELAY, REQUEST_METRICS_HISTORY, RESPONSE_CACHE_CAPACITY};
+use crate::types::{
+    config::{InitConfig, RouteRule},
+    error::L8Error,
+    http_caller::HttpCaller,
+    log_level::LogLevel,
+    metrics::RequestMetrics,
+    network_state::{NetworkState, NetworkStateErrored, NetworkStateOpen, ProviderSchemes},
+    response::CachedResponse,
+};
+use crate::{init_tunnel, utils};
 
-use std::{cell::RefCell, collections::HashMap, rc::Rc};
-use wasm_bindgen::JsValue;
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    rc::Rc,
+};
+use wasm_bindgen::{JsCast, JsValue};
 use web_sys::console;
 
 thread_local! {
@@ -12,20 +24,77 @@ thread_local! {
     /// It maps a provider name (e.g., "https://provider.com") to its corresponding `NetworkState`.
     static NETWORK_STATE_MAP: RefCell<HashMap<String, Rc<NetworkState>>> = RefCell::new(HashMap::new());
 
-    /// This is a flag to indicate if the dev mode is enabled. It is used to enable or disable the dev mode features like logging.
-    static DEV_FLAG: RefCell<bool> = const { RefCell::new(false) };
+    /// The callbacks registered with `onTunnelStateChange`, called on every network state transition.
+    static TUNNEL_STATE_LISTENERS: RefCell<Vec<js_sys::Function>> = const { RefCell::new(Vec::new()) };
+
+    /// The providers with an `init_tunnel` handshake currently in flight. Concurrent reinitializations of the
+    /// same provider wait for the in-flight handshake instead of starting their own.
+    static INIT_IN_FLIGHT: RefCell<HashSet<String>> = RefCell::new(HashSet::new());
+
+    /// The configuration passed to the last `initEncryptedTunnel` call.
+    static CONFIG: RefCell<InitConfig> = RefCell::new(InitConfig::default());
+
+    /// The verbosity of the logs. Dev mode features like the request metrics are enabled at the `Debug` level.
+    static LOG_LEVEL: Cell<LogLevel> = const { Cell::new(LogLevel::Error) };
+
+    /// The last access time handed to an IndexedDB entry, so that two accesses in the same millisecond are still ordered.
+    static LAST_CACHE_ACCESS: Cell<f64> = const { Cell::new(0.0) };
+
+    /// The values of the IndexedDB key-value calls when IndexedDB is unavailable (e.g. in private browsing), keyed by
+    /// object store and versioned key, along with their last access time. They don't outlive the page, and the least
+    /// recently used ones are evicted past `cacheMaxBytes` like the IndexedDB entries.
+    static IDB_FALLBACK: RefCell<BTreeMap<(String, String), (Vec<u8>, f64)>> = const { RefCell::new(BTreeMap::new()) };
+
+    /// The values of `idb_append` when IndexedDB is unavailable, per object store and in key order, along with their
+    /// key. Like `IDB_FALLBACK` they don't outlive the page.
+    static IDB_FALLBACK_APPENDED: RefCell<BTreeMap<String, Vec<(f64, JsValue)>>> = const { RefCell::new(BTreeMap::new()) };
+
+    /// Whether the fallback to `IDB_FALLBACK` was already warned about, it is only logged once.
+    static IDB_FALLBACK_WARNED: Cell<bool> = const { Cell::new(false) };
+
+    /// The callback installed with `setLogger`, logs go to the console when it is not set.
+    static LOGGER: RefCell<Option<js_sys::Function>> = const { RefCell::new(None) };
+
+    /// The `globalThis.fetch` replaced by `installGlobalFetch`, restored by `uninstallGlobalFetch`.
+    static ORIGINAL_FETCH: RefCell<Option<js_sys::Function>> = const { RefCell::new(None) };
+
+    /// The `resolve` and `reject` functions of the `fetch` calls waiting for their queued request to be replayed,
+    /// keyed by the bits of the IndexedDB key of the request.
+    static QUEUE_WAITERS: RefCell<HashMap<u64, (js_sys::Function, js_sys::Function)>> = RefCell::new(HashMap::new());
+
+    /// The cacheable GET/HEAD responses, keyed by `L8RequestObject::response_cache_key` and capped at
+    /// `RESPONSE_CACHE_CAPACITY` entries.
+    static RESPONSE_CACHE: RefCell<HashMap<String, CachedResponse>> = RefCell::new(HashMap::new());
+
+    /// The schemes of the forward proxy and of the backend of every `OPEN` tunnel, keyed by network state key.
+    static PROVIDER_SCHEMES: RefCell<HashMap<String, ProviderSchemes>> = RefCell::new(HashMap::new());
+
+    /// The crypto timings of the most recent requests, capped at `REQUEST_METRICS_HISTORY` entries. Only populated in dev mode.
+    static REQUEST_METRICS: RefCell<VecDeque<RequestMetrics>> = const { RefCell::new(VecDeque::new()) };
 }
 
 pub(crate) struct InMemoryCache {}
 
 impl InMemoryCache {
-    pub(crate) async fn get_network_state(provider_url: &str) -> Result<NetworkStateOpen, JsValue> {
-        let dev_flag = DEV_FLAG.with_borrow(|flag| *flag);
+    /// Waits for the tunnel of the provider to be `OPEN`, retrying a stale `ERRORED` one (see `erroredRetryAfterMs`)
+    /// with `http_caller` reaching the proxy.
+    pub(crate) async fn get_network_state(
+        provider_url: &str,
+        http_caller: impl HttpCaller + 'static,
+    ) -> Result<NetworkStateOpen, JsValue> {
+        let (timeout, errored_retry_after) = CONFIG.with_borrow(|config| {
+            (
+                config.connecting_timeout_ms as f64,
+                config.errored_retry_after_ms,
+            )
+        });
+        let mut started_at = js_sys::Date::now();
+        let mut retried = false;
         loop {
             let network_state = NETWORK_STATE_MAP
                 .with_borrow(|cache| cache.get(provider_url).map(Rc::clone))
                 .ok_or_else(|| {
-                    JsValue::from_str(&format!(
+                    L8Error::NotInitialized(format!(
                         "Network state for {} is not initialized. Please call `await layer8.initEncryptedTunnel(..)` first.",
                         provider_url
                     ))
@@ -33,15 +102,46 @@ impl InMemoryCache {
 
             match network_state.as_ref() {
                 NetworkState::OPEN(state) => return Ok(state.clone()),
-                NetworkState::ERRORED(err) => return Err(err.clone()),
+                NetworkState::ERRORED(state) => {
+                    // a stale failure gets one more chance, we then wait for the new handshake like any CONNECTING tunnel
+                    let stale = errored_retry_after.is_some_and(|retry_after| {
+                        js_sys::Date::now() - state.errored_at >= retry_after as f64
+                    });
+
+                    if !stale || retried {
+                        return Err(state.error.clone());
+                    }
+
+                    Self::log(
+                        LogLevel::Info,
+                        &format!("Retrying the errored tunnel for {}", provider_url).into(),
+                    );
+
+                    retried = true;
+                    started_at = js_sys::Date::now();
+                    init_tunnel::schedule_init_tunnel_with(
+                        provider_url.to_string(),
+                        state.forward_proxy_url.clone(),
+                        state.http_client.clone(),
+                        http_caller.clone(),
+                    );
+                }
                 NetworkState::CONNECTING => {
-                    if dev_flag {
-                        console::log_1(
-                            &format!("Waiting for network state to be OPEN for {}", provider_url)
-                                .into(),
-                        );
+                    // the background handshake may never settle (e.g. the task panicked), don't hang forever
+                    if js_sys::Date::now() - started_at >= timeout {
+                        return Err(L8Error::TimeoutError(format!(
+                            "Timed out after {}ms waiting for the tunnel to {} to open",
+                            timeout, provider_url
+                        ))
+                        .into());
                     }
 
+                    Self::log(
+                        LogLevel::Debug,
+                        &format!("Waiting for network state to be OPEN for {}", provider_url)
+                            .into(),
+                    );
+
                     utils::sleep(FETCH_RETRY_SLEEP_DELAY).await; // wait before checking
                     continue;
                 }
@@ -50,38 +150,459 @@ impl InMemoryCache {
     }
 
     pub(crate) fn set_connecting_network_state(provider_url: &str) {
-        NETWORK_STATE_MAP.with_borrow_mut(|cache| {
-            cache.insert(provider_url.to_string(), Rc::new(NetworkState::CONNECTING));
-        });
+        Self::set_network_state(provider_url, NetworkState::CONNECTING);
     }
 
     pub(crate) fn set_open_network_state(provider_url: &str, state: NetworkStateOpen) {
-        NETWORK_STATE_MAP.with_borrow_mut(|cache| {
-            cache.insert(provider_url.to_string(), Rc::new(NetworkState::OPEN(state)));
-        });
+        // the proxy may change on every handshake (see `fallbackForwardProxyUrls`), the backend never does
+        match ProviderSchemes::new(
+            &state.forward_proxy_url,
+            utils::session_base_url(provider_url),
+        ) {
+            Ok(schemes) => PROVIDER_SCHEMES.with_borrow_mut(|all| {
+                all.insert(provider_url.to_string(), schemes);
+            }),
+            Err(err) => Self::log(LogLevel::Warn, &err),
+        }
+
+        Self::set_network_state(provider_url, NetworkState::OPEN(state));
+    }
+
+    pub(crate) fn get_provider_schemes(provider_url: &str) -> Option<ProviderSchemes> {
+        PROVIDER_SCHEMES.with_borrow(|all| all.get(provider_url).cloned())
     }
 
-    pub(crate) fn set_errored_network_state(provider_url: &str, err: JsValue) {
-        NETWORK_STATE_MAP.with_borrow_mut(|cache| {
-            cache.insert(
-                provider_url.to_string(),
-                Rc::new(NetworkState::ERRORED(err)),
+    pub(crate) fn set_errored_network_state(provider_url: &str, state: NetworkStateErrored) {
+        Self::set_network_state(provider_url, NetworkState::ERRORED(state));
+    }
+
+    /// Replaces the network state of the provider, notifying the `onTunnelStateChange` listeners when it moves to
+    /// another state. `oldState` is `null` for the first state of the provider.
+    fn set_network_state(provider_url: &str, state: NetworkState) {
+        let new_state = state.name();
+        let old_state = NETWORK_STATE_MAP.with_borrow_mut(|cache| {
+            cache
+                .insert(provider_url.to_string(), Rc::new(state))
+                .map(|old| old.name())
+        });
+        if old_state == Some(new_state) {
+            return;
+        }
+
+        // cloned so that a listener registering another one doesn't run into the borrow
+        let listeners = TUNNEL_STATE_LISTENERS.with_borrow(|listeners| listeners.clone());
+        for listener in listeners {
+            // a throwing listener must not prevent the transition nor the other listeners
+            _ = listener.call3(
+                &JsValue::NULL,
+                &provider_url.into(),
+                &old_state.map_or(JsValue::NULL, JsValue::from),
+                &new_state.into(),
             );
+        }
+    }
+
+    pub(crate) fn add_tunnel_state_listener(listener: js_sys::Function) {
+        TUNNEL_STATE_LISTENERS.with_borrow_mut(|listeners| listeners.push(listener));
+    }
+
+    /// Whether the provider has a tunnel that is either usable or about to be.
+    pub(crate) fn is_open_or_connecting(provider_url: &str) -> bool {
+        NETWORK_STATE_MAP.with_borrow(|cache| {
+            cache.get(provider_url).is_some_and(|state| {
+                matches!(
+                    state.as_ref(),
+                    NetworkState::OPEN(_) | NetworkState::CONNECTING
+                )
+            })
+        })
+    }
+
+    /// Returns the network state of the provider if its tunnel is `OPEN`, without waiting for a `CONNECTING` one.
+    pub(crate) fn get_open_network_state(provider_url: &str) -> Option<NetworkStateOpen> {
+        NETWORK_STATE_MAP.with_borrow(|cache| match cache.get(provider_url)?.as_ref() {
+            NetworkState::OPEN(state) => Some(state.clone()),
+            _ => None,
+        })
+    }
+
+    /// Whether the provider has been initialized, whatever the state of its tunnel.
+    pub(crate) fn has_network_state(provider_url: &str) -> bool {
+        NETWORK_STATE_MAP.with_borrow(|cache| cache.contains_key(provider_url))
+    }
+
+    /// Returns the forward proxy url and http client of the provider if its network state is `ERRORED`.
+    pub(crate) fn get_errored_network_state(
+        provider_url: &str,
+    ) -> Option<(String, reqwest::Client)> {
+        NETWORK_STATE_MAP.with_borrow(|cache| match cache.get(provider_url)?.as_ref() {
+            NetworkState::ERRORED(state) => {
+                Some((state.forward_proxy_url.clone(), state.http_client.clone()))
+            }
+            _ => None,
+        })
+    }
+
+    /// Claims the handshake for the provider. Returns `false` if another handshake is already in flight,
+    /// in which case the caller should wait for the network state to leave `CONNECTING` instead.
+    pub(crate) fn try_begin_init(provider_url: &str) -> bool {
+        INIT_IN_FLIGHT.with_borrow_mut(|in_flight| in_flight.insert(provider_url.to_string()))
+    }
+
+    pub(crate) fn end_init(provider_url: &str) {
+        INIT_IN_FLIGHT.with_borrow_mut(|in_flight| {
+            in_flight.remove(provider_url);
         });
     }
 
-    pub(crate) fn set_dev_flag(flag: Option<bool>) -> bool {
-        if let Some(val) = flag {
-            if val {
-                DEV_FLAG.with_borrow_mut(|dev_flag| *dev_flag = true);
-                console::log_1(&"Dev mode enabled".into());
-                return true;
+    /// Replaces the config, keeping the per-provider settings of the providers the new one leaves out.
+    pub(crate) fn set_config(mut config: InitConfig) {
+        CONFIG.with_borrow_mut(|current| {
+            config.inherit_provider_settings(current);
+            *current = config;
+        });
+    }
+
+    pub(crate) fn get_strict_options() -> bool {
+        CONFIG.with_borrow(|config| config.strict_options)
+    }
+
+    pub(crate) fn get_cache_version() -> String {
+        CONFIG.with_borrow(|config| config.cache_version.clone())
+    }
+
+    pub(crate) fn get_cache_max_bytes() -> Option<f64> {
+        CONFIG.with_borrow(|config| config.cache_max_bytes)
+    }
+
+    pub(crate) fn get_filter_exposed_headers() -> bool {
+        CONFIG.with_borrow(|config| config.filter_exposed_headers)
+    }
+
+    pub(crate) fn get_max_request_body_bytes() -> Option<u32> {
+        CONFIG.with_borrow(|config| config.max_request_body_bytes)
+    }
+
+    pub(crate) fn get_max_stream_bytes() -> Option<u32> {
+        CONFIG.with_borrow(|config| config.max_stream_bytes)
+    }
+
+    pub(crate) fn get_on_timing() -> Option<js_sys::Function> {
+        CONFIG.with_borrow(|config| config.on_timing.dyn_ref::<js_sys::Function>().cloned())
+    }
+
+    pub(crate) fn get_init_retries() -> u32 {
+        CONFIG.with_borrow(|config| config.init_retries)
+    }
+
+    pub(crate) fn get_fetch_retries() -> u32 {
+        CONFIG.with_borrow(|config| config.fetch_retries)
+    }
+
+    /// The forward proxies to handshake through, in failover order: the primary and fallback ones starting from
+    /// `current` and wrapping around. With `rotate`, `current` (which just failed) is tried last instead of first.
+    pub(crate) fn get_forward_proxy_urls(current: &str, rotate: bool) -> Vec<String> {
+        let mut configured = CONFIG.with_borrow(|config| {
+            std::iter::once(&config.forward_proxy_url)
+                .chain(&config.fallback_forward_proxy_urls)
+                .filter(|url| !url.is_empty())
+                .cloned()
+                .collect::<Vec<_>>()
+        });
+        let start = configured
+            .iter()
+            .position(|url| url == current)
+            .unwrap_or(0);
+        configured.rotate_left(start);
+
+        let mut urls = vec![current.to_string()];
+        for url in configured {
+            if !urls.contains(&url) {
+                urls.push(url);
             }
         }
-        return false;
+
+        if rotate {
+            urls.rotate_left(1);
+        }
+
+        urls
+    }
+
+    pub(crate) fn get_passthrough_uninitialized() -> bool {
+        CONFIG.with_borrow(|config| config.passthrough_uninitialized)
+    }
+
+    pub(crate) fn get_queue_offline() -> bool {
+        CONFIG.with_borrow(|config| config.queue_offline)
+    }
+
+    pub(crate) fn get_queue_timeout_ms() -> u32 {
+        CONFIG.with_borrow(|config| config.queue_timeout_ms)
+    }
+
+    pub(crate) fn get_on_queue_flushed() -> Option<js_sys::Function> {
+        CONFIG.with_borrow(|config| {
+            config
+                .on_queue_flushed
+                .dyn_ref::<js_sys::Function>()
+                .cloned()
+        })
+    }
+
+    /// The `onInspect` callback, only handed out in dev mode so that plaintext never leaks in production.
+    pub(crate) fn get_on_inspect() -> Option<js_sys::Function> {
+        if !Self::get_dev_flag() {
+            return None;
+        }
+
+        CONFIG.with_borrow(|config| config.on_inspect.dyn_ref::<js_sys::Function>().cloned())
+    }
+
+    pub(crate) fn get_emulate_preflight() -> bool {
+        CONFIG.with_borrow(|config| config.emulate_preflight)
+    }
+
+    pub(crate) fn add_queue_waiter(key: f64, settle: (js_sys::Function, js_sys::Function)) {
+        QUEUE_WAITERS.with_borrow_mut(|waiters| {
+            waiters.insert(key.to_bits(), settle);
+        });
+    }
+
+    pub(crate) fn take_queue_waiter(key: f64) -> Option<(js_sys::Function, js_sys::Function)> {
+        QUEUE_WAITERS.with_borrow_mut(|waiters| waiters.remove(&key.to_bits()))
+    }
+
+    pub(crate) fn is_custom_method(method: &str) -> bool {
+        CONFIG.with_borrow(|config| {
+            config
+                .custom_methods
+                .iter()
+                .any(|custom| custom.eq_ignore_ascii_case(method))
+        })
+    }
+
+    pub(crate) fn get_pinned_server_key(provider_url: &str) -> Option<Vec<u8>> {
+        CONFIG.with_borrow(|config| config.pinned_server_keys.get(provider_url).cloned())
+    }
+
+    /// The `headerAllowlist` and `headerDenylist` of the provider, `None` when it has no allowlist.
+    pub(crate) fn get_header_policy(provider_url: &str) -> (Option<Vec<String>>, Vec<String>) {
+        CONFIG.with_borrow(|config| {
+            (
+                config.header_allowlist.get(provider_url).cloned(),
+                config
+                    .header_denylist
+                    .get(provider_url)
+                    .cloned()
+                    .unwrap_or_default(),
+            )
+        })
+    }
+
+    pub(crate) fn get_allowed_routes(provider_url: &str) -> Option<Vec<RouteRule>> {
+        CONFIG.with_borrow(|config| config.allowed_routes.get(provider_url).cloned())
+    }
+
+    pub(crate) fn get_retry_predicate() -> Option<js_sys::Function> {
+        CONFIG.with_borrow(|config| {
+            config
+                .retry_predicate
+                .dyn_ref::<js_sys::Function>()
+                .cloned()
+        })
     }
 
+    /// The number of request logs persisted to IndexedDB, `None` unless both the dev mode and `persistRequestLogs` are on.
+    pub(crate) fn get_request_log_capacity() -> Option<u32> {
+        if !Self::get_dev_flag() {
+            return None;
+        }
+
+        CONFIG.with_borrow(|config| config.persist_request_logs)
+    }
+
+    /// Returns the current time in milliseconds, strictly greater than the previously returned one.
+    pub(crate) fn next_cache_access_time() -> f64 {
+        LAST_CACHE_ACCESS.with(|last| {
+            let now = js_sys::Date::now().max(last.get() + 0.001);
+            last.set(now);
+            now
+        })
+    }
+
+    /// Logs, once, that IndexedDB is unavailable and values are only kept in memory.
+    pub(crate) fn warn_idb_unavailable(reason: &JsValue) {
+        if IDB_FALLBACK_WARNED.replace(true) {
+            return;
+        }
+
+        InMemoryCache::log(
+            LogLevel::Warn,
+            &format!(
+                "IndexedDB is unavailable, values are kept in memory until the page is closed: {:?}",
+                reason
+            )
+            .into(),
+        );
+    }
+
+    pub(crate) fn idb_fallback_put(store: &str, key: &str, value: Vec<u8>) {
+        let last_access = Self::next_cache_access_time();
+        let budget = Self::get_cache_max_bytes();
+        IDB_FALLBACK.with_borrow_mut(|values| {
+            values.insert((store.to_string(), key.to_string()), (value, last_access));
+
+            let Some(budget) = budget else {
+                return;
+            };
+            let mut stored_bytes = values
+                .values()
+                .map(|(value, _)| value.len() as f64)
+                .sum::<f64>();
+            while stored_bytes > budget {
+                let Some(oldest) = values
+                    .iter()
+                    .min_by(|(_, (_, a)), (_, (_, b))| a.total_cmp(b))
+                    .map(|(entry, _)| entry.clone())
+                else {
+                    break;
+                };
+                if let Some((value, _)) = values.remove(&oldest) {
+                    stored_bytes -= value.len() as f64;
+                }
+            }
+        });
+    }
+
+    /// Appends the value under `key`, dropping the oldest values past `capacity`.
+    pub(crate) fn idb_fallback_append(store: &str, key: f64, value: JsValue, capacity: u32) {
+        IDB_FALLBACK_APPENDED.with_borrow_mut(|stores| {
+            let values = stores.entry(store.to_string()).or_default();
+            values.push((key, value));
+            let overflow = values.len().saturating_sub(capacity as usize);
+            values.drain(..overflow);
+        });
+    }
+
+    /// The appended keys and values of the store, in key order.
+    pub(crate) fn idb_fallback_entries(store: &str) -> Vec<(f64, JsValue)> {
+        IDB_FALLBACK_APPENDED.with_borrow(|stores| stores.get(store).cloned().unwrap_or_default())
+    }
+
+    /// Removes the appended value stored under `key` and returns it, `None` when there is none.
+    pub(crate) fn idb_fallback_remove(store: &str, key: f64) -> Option<JsValue> {
+        IDB_FALLBACK_APPENDED.with_borrow_mut(|stores| {
+            let values = stores.get_mut(store)?;
+            let index = values.iter().position(|(appended, _)| *appended == key)?;
+            Some(values.remove(index).1)
+        })
+    }
+
+    pub(crate) fn idb_fallback_get(store: &str, key: &str) -> Option<Vec<u8>> {
+        let last_access = Self::next_cache_access_time();
+        IDB_FALLBACK.with_borrow_mut(|values| {
+            let (value, accessed) = values.get_mut(&(store.to_string(), key.to_string()))?;
+            *accessed = last_access;
+            Some(value.clone())
+        })
+    }
+
+    pub(crate) fn idb_fallback_delete(store: &str, key: &str) {
+        IDB_FALLBACK.with_borrow_mut(|values| values.remove(&(store.to_string(), key.to_string())));
+    }
+
+    /// The keys of the store, in key order.
+    pub(crate) fn idb_fallback_keys(store: &str) -> Vec<String> {
+        IDB_FALLBACK.with_borrow(|values| {
+            values
+                .keys()
+                .filter(|(key_store, _)| key_store == store)
+                .map(|(_, key)| key.clone())
+                .collect()
+        })
+    }
+
+    pub(crate) fn idb_fallback_clear(store: &str) {
+        IDB_FALLBACK
+            .with_borrow_mut(|values| values.retain(|(key_store, _), _| key_store != store));
+    }
+
+    pub(crate) fn set_log_level(level: LogLevel) {
+        LOG_LEVEL.with(|current| current.set(level));
+        Self::log(LogLevel::Debug, &"Dev mode enabled".into());
+    }
+
+    /// Dev mode is the `Debug` log level.
     pub(crate) fn get_dev_flag() -> bool {
-        DEV_FLAG.with_borrow(|dev_flag| *dev_flag)
+        LOG_LEVEL.with(|current| current.get().allows(LogLevel::Debug))
+    }
+
+    pub(crate) fn set_logger(logger: Option<js_sys::Function>) {
+        LOGGER.with_borrow_mut(|current| *current = logger);
+    }
+
+    pub(crate) fn get_original_fetch() -> Option<js_sys::Function> {
+        ORIGINAL_FETCH.with_borrow(|original| original.clone())
+    }
+
+    pub(crate) fn set_original_fetch(
+        original: Option<js_sys::Function>,
+    ) -> Option<js_sys::Function> {
+        ORIGINAL_FETCH.with_borrow_mut(|current| std::mem::replace(current, original))
+    }
+
+    /// Logs the message, to the `setLogger` callback or the console, if the configured log level lets its level through.
+    pub(crate) fn log(level: LogLevel, message: &JsValue) {
+        if !LOG_LEVEL.with(|current| current.get().allows(level)) {
+            return;
+        }
+
+        // cloned out of the cell, the callback may well log or install another logger itself
+        if let Some(logger) = LOGGER.with_borrow(|logger| logger.clone()) {
+            // a throwing logger must not break the request being logged
+            _ = logger.call2(&JsValue::NULL, &level.as_str().into(), message);
+            return;
+        }
+
+        match level {
+            LogLevel::Error => console::error_1(message),
+            LogLevel::Warn => console::warn_1(message),
+            _ => console::log_1(message),
+        }
+    }
+
+    pub(crate) fn get_cached_response(key: &str) -> Option<CachedResponse> {
+        RESPONSE_CACHE.with_borrow(|cache| cache.get(key).cloned())
+    }
+
+    /// Caches the response, evicting the one expiring first when the cache is full.
+    pub(crate) fn cache_response(key: &str, response: CachedResponse) {
+        RESPONSE_CACHE.with_borrow_mut(|cache| {
+            if cache.len() >= RESPONSE_CACHE_CAPACITY && !cache.contains_key(key) {
+                let evicted = cache
+                    .iter()
+                    .min_by(|(_, a), (_, b)| a.expires_at.total_cmp(&b.expires_at))
+                    .map(|(key, _)| key.clone());
+                if let Some(evicted) = evicted {
+                    cache.remove(&evicted);
+                }
+            }
+
+            cache.insert(key.to_string(), response);
+        });
+    }
+
+    pub(crate) fn push_request_metrics(metrics: RequestMetrics) {
+        REQUEST_METRICS.with_borrow_mut(|history| {
+            if history.len() >= REQUEST_METRICS_HISTORY {
+                history.pop_front();
+            }
+            history.push_back(metrics);
+        });
+    }
+
+    pub(crate) fn get_request_metrics() -> Vec<RequestMetrics> {
+        REQUEST_METRICS.with_borrow(|history| history.iter().cloned().collect())
     }
 }
@@ -0,0 +1,209 @@
+use serde::Deserialize;
+use std::collections::HashMap;
+use wasm_bindgen::JsValue;
+
+use crate::constants;
+use crate::types::log_level::LogLevel;
+use crate::utils;
+
+/// Optional configuration accepted by `initEncryptedTunnel`. Every field can be omitted, in which case
+/// its default is used.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase", default)]
+pub struct InitConfig {
+    /// Maximum time in milliseconds a request waits for a `CONNECTING` tunnel before failing.
+    pub connecting_timeout_ms: u32,
+    /// When set, a request hitting a tunnel that has been `ERRORED` for at least this many milliseconds
+    /// retries the initialization once instead of failing right away. Disabled by default.
+    pub errored_retry_after_ms: Option<u32>,
+    /// Rejects fetch calls whose options object has keys that are neither standard `RequestInit` members
+    /// nor Layer8 options, to catch typos like `credential`. Disabled by default.
+    pub strict_options: bool,
+    /// Namespaces the IndexedDB entries, bumping it (e.g. on a new app release) invalidates everything stored
+    /// under the previous version. Entries of other versions are never served.
+    pub cache_version: String,
+    /// The number of bytes the IndexedDB entries may take, the least recently used ones are evicted past it.
+    /// Unbounded by default, entries are then only evicted when the browser storage quota is exceeded.
+    pub cache_max_bytes: Option<f64>,
+    /// Only lets JS read the response headers the backend exposes through `Access-Control-Expose-Headers`,
+    /// like a cross-origin `fetch` would. Disabled by default, every response header is readable.
+    pub filter_exposed_headers: bool,
+    /// Enables the dev mode, i.e. the `debug` log level unless `logLevel` says otherwise. Takes precedence over the
+    /// positional `dev_flag` argument of `initEncryptedTunnel`.
+    pub dev_flag: Option<bool>,
+    /// One of `"off"`, `"error"`, `"warn"`, `"info"` or `"debug"`. When omitted, `devFlag` decides:
+    /// `true` logs everything (`debug`) and `false` only errors (`error`).
+    pub log_level: Option<LogLevel>,
+    /// The largest request body the proxy accepts. Bigger bodies are rejected before being encrypted and sent.
+    pub max_request_body_bytes: Option<u32>,
+    /// The most bytes read from a `ReadableStream` body before giving up with `BodyTooLarge`. Streams are read to
+    /// their end by default.
+    pub max_stream_bytes: Option<u32>,
+    /// Called with the `RequestTiming` of every successful `fetch`. Nothing is measured when it is not set.
+    #[serde(with = "serde_wasm_bindgen::preserve")]
+    pub on_timing: JsValue,
+    /// In dev mode, keeps the redacted metadata of this many of the most recent requests in IndexedDB, to be
+    /// retrieved with `exportLogs`. Nothing is persisted by default.
+    pub persist_request_logs: Option<u32>,
+    /// Called as `(status, errorKind, attempt) => boolean` when a proxied request fails with a tunnel error (see
+    /// `is_tunnel_error`), to decide whether the tunnel is reinitialized and the request retried. `status` is `0`
+    /// when the proxy could not be reached and `errorKind` is the name of the `L8Error` the request would otherwise
+    /// fail with. The retry attempts are still bounded. Without it every failure is retried.
+    #[serde(with = "serde_wasm_bindgen::preserve")]
+    pub retry_predicate: JsValue,
+    /// The expected NTor static public key (an array of 32 bytes) of a provider, keyed by its base url. The handshake
+    /// of a pinned provider fails with `CertPinMismatch` when the proxy presents another key. Nothing is pinned by
+    /// default. A later `initEncryptedTunnel` call only replaces the keys of the providers it pins again.
+    pub pinned_server_keys: HashMap<String, Vec<u8>>,
+    /// The only request headers tunneled to a provider, keyed by its base url. Every header is tunneled to the
+    /// providers without one, the `Content-Type` and `Content-Length` set by the interceptor have to be listed too.
+    /// Like the pins, a later `initEncryptedTunnel` call only replaces the lists of the providers it lists again.
+    pub header_allowlist: HashMap<String, Vec<String>>,
+    /// The request headers never tunneled to a provider (e.g. `Authorization`), keyed by its base url. Takes
+    /// precedence over `headerAllowlist`.
+    pub header_denylist: HashMap<String, Vec<String>>,
+    /// The only routes tunneled to a provider, keyed by its base url. Requests to a provider with routes fail with
+    /// `RouteNotAllowed` unless one of them matches, before anything is encrypted or sent. Every route of the
+    /// providers without any is tunneled. A later `initEncryptedTunnel` call only replaces the routes of the
+    /// providers it lists again.
+    pub allowed_routes: HashMap<String, Vec<RouteRule>>,
+    /// Maximum attempts to send the `init-tunnel` request of a handshake, backing off between them.
+    pub init_retries: u32,
+    /// Maximum times a `fetch` reinitializes the tunnel and retries the request after a failure.
+    pub fetch_retries: u32,
+    /// Forward proxies to fail over to, in order, when the handshake through the primary one fails. A tunnel whose
+    /// proxy fails later on is reinitialized through the next one, wrapping around the list.
+    pub fallback_forward_proxy_urls: Vec<String>,
+    /// Non-standard HTTP methods (e.g. `PROPFIND`) requests may use on top of the standard ones, any other method
+    /// is rejected with `InvalidMethod`. Matched case-insensitively.
+    pub custom_methods: Vec<String>,
+    /// Sends the requests to providers that have not been initialized (analytics, CDNs...) with the native `fetch`
+    /// instead of failing them with `NotInitialized`. Disabled by default.
+    pub passthrough_uninitialized: bool,
+    /// Keeps the idempotent requests (and the ones with the `queueOffline: true` fetch option) issued while their
+    /// provider is `ERRORED` or the browser offline in IndexedDB, instead of failing them. They are replayed in order,
+    /// and their `fetch` calls resolved, when the tunnel reopens or on `flushQueue`. Disabled by default.
+    ///
+    /// The queued requests survive reloads. Their `Cookie` header is never persisted, the replay attaches the
+    /// document cookies again as its `credentials` allow, but the other headers are, `Authorization` included.
+    pub queue_offline: bool,
+    /// Maximum time in milliseconds a queued request waits for its replay before its `fetch` call fails with a
+    /// `TimeoutError`, the request is then dropped from the queue.
+    pub queue_timeout_ms: u32,
+    /// Called as `(provider, replayed)` after queued requests of the provider have been replayed.
+    #[serde(with = "serde_wasm_bindgen::preserve")]
+    pub on_queue_flushed: JsValue,
+    /// In dev mode, called with an `InspectEvent` carrying the plaintext of every tunneled request right before it
+    /// is encrypted, and of its response right after it is decrypted. Ignored outside of the dev mode.
+    #[serde(with = "serde_wasm_bindgen::preserve")]
+    pub on_inspect: JsValue,
+    /// Sends the CORS preflight the browser would send for cross-origin requests that are not simple ones, as an
+    /// `OPTIONS` request through the tunnel, and fails the request unless its `Access-Control-Allow-*` headers allow
+    /// it. Off by default, the tunnel bypasses CORS.
+    pub emulate_preflight: bool,
+    /// The primary forward proxy, i.e. the `forward_proxy_url` argument of `initEncryptedTunnel`.
+    #[serde(skip)]
+    pub forward_proxy_url: String,
+}
+
+impl Default for InitConfig {
+    fn default() -> Self {
+        InitConfig {
+            connecting_timeout_ms: constants::CONNECTING_WAIT_TIMEOUT,
+            errored_retry_after_ms: None,
+            strict_options: false,
+            cache_version: String::new(),
+            cache_max_bytes: None,
+            filter_exposed_headers: false,
+            dev_flag: None,
+            log_level: None,
+            max_request_body_bytes: None,
+            max_stream_bytes: None,
+            on_timing: JsValue::UNDEFINED,
+            persist_request_logs: None,
+            retry_predicate: JsValue::UNDEFINED,
+            pinned_server_keys: HashMap::new(),
+            header_allowlist: HashMap::new(),
+            header_denylist: HashMap::new(),
+            allowed_routes: HashMap::new(),
+            init_retries: constants::INIT_TUNNEL_RETRY_ATTEMPTS,
+            fetch_retries: constants::FETCH_RETRY_ATTEMPTS,
+            fallback_forward_proxy_urls: Vec::new(),
+            custom_methods: Vec::new(),
+            passthrough_uninitialized: false,
+            queue_offline: false,
+            queue_timeout_ms: constants::QUEUE_WAIT_TIMEOUT,
+            on_queue_flushed: JsValue::UNDEFINED,
+            on_inspect: JsValue::UNDEFINED,
+            emulate_preflight: false,
+            forward_proxy_url: String::new(),
+        }
+    }
+}
+
+impl InitConfig {
+    pub(crate) fn from_js(config: Option<js_sys::Object>) -> Result<Self, JsValue> {
+        let mut config: InitConfig = match config {
+            Some(config) => serde_wasm_bindgen::from_value(config.into())
+                .map_err(|e| JsValue::from_str(&format!("Invalid init config: {}", e)))?,
+            None => InitConfig::default(),
+        };
+
+        config.pinned_server_keys = by_base_url(config.pinned_server_keys, "pinnedServerKeys")?;
+        config.header_allowlist = by_base_url(config.header_allowlist, "headerAllowlist")?;
+        config.header_denylist = by_base_url(config.header_denylist, "headerDenylist")?;
+        config.allowed_routes = by_base_url(config.allowed_routes, "allowedRoutes")?;
+
+        Ok(config)
+    }
+
+    /// Keeps the per-provider settings of the `previous` config for the providers this one leaves out, so that a
+    /// later `initEncryptedTunnel` call never silently lifts a pin, a header list or a route restriction.
+    pub(crate) fn inherit_provider_settings(&mut self, previous: &InitConfig) {
+        inherit(&mut self.pinned_server_keys, &previous.pinned_server_keys);
+        inherit(&mut self.header_allowlist, &previous.header_allowlist);
+        inherit(&mut self.header_denylist, &previous.header_denylist);
+        inherit(&mut self.allowed_routes, &previous.allowed_routes);
+    }
+}
+
+/// Keys the per-provider setting by the base url of each provider, the one it is looked up with. A key that is no
+/// valid url is rejected rather than never matching.
+fn by_base_url<T>(settings: HashMap<String, T>, name: &str) -> Result<HashMap<String, T>, JsValue> {
+    settings
+        .into_iter()
+        .map(
+            |(provider_url, setting)| match utils::get_base_url(&provider_url) {
+                Ok(base_url) => Ok((base_url, setting)),
+                Err(_) => Err(JsValue::from_str(&format!(
+                    "Invalid init config: {} of {} is not a valid url",
+                    provider_url, name
+                ))),
+            },
+        )
+        .collect()
+}
+
+fn inherit<T: Clone>(settings: &mut HashMap<String, T>, previous: &HashMap<String, T>) {
+    for (provider_url, setting) in previous {
+        settings
+            .entry(provider_url.clone())
+            .or_insert_with(|| setting.clone());
+    }
+}
+
+/// A route of the `allowedRoutes` init config, e.g. `{ method: "GET", path: "/api/users/*" }`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct RouteRule {
+    /// The HTTP method of the route, matched case-insensitively. `*` matches any method.
+    pub method: String,
+    /// The path of the route, without the query string. `*` matches any run of characters, `/` included.
+    pub path: String,
+}
+
+impl RouteRule {
+    pub fn matches(&self, method: &str, path: &str) -> bool {
+        (self.method == "*" || self.method.eq_ignore_ascii_case(method))
+            && utils::glob_match(&self.path, path)
+    }
+}
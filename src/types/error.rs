@@ -0,0 +1,130 @@
+use serde::Serialize;
+use wasm_bindgen::JsValue;
+
+/// The errors surfaced to JS by `fetch` and the tunnel initialization. They are converted to plain
+/// objects of the shape `{ name, code, message, status? }` so callers can branch on `name`/`code`
+/// instead of matching on the message.
+#[derive(Debug, Clone)]
+pub enum L8Error {
+    /// The request is malformed and was not sent.
+    InvalidRequest(String),
+    /// The request method is neither a standard HTTP method nor one of the `customMethods` of the init config.
+    InvalidMethod(String),
+    /// The forward proxy could not be reached or its response could not be read.
+    NetworkError(String),
+    /// The forward proxy answered with an unexpected status.
+    ProxyError { status: u16, message: String },
+    /// Encrypting, decrypting or the NTor handshake failed.
+    CryptoError(String),
+    /// The request was aborted through its `AbortSignal`.
+    AbortError(String),
+    /// Waiting on the tunnel took longer than allowed.
+    TimeoutError(String),
+    /// No tunnel was initialized for the requested provider.
+    NotInitialized(String),
+    /// The proxy's static public key differs from the one pinned for the provider in the init config.
+    CertPinMismatch(String),
+    /// A `ReadableStream` body holds more bytes than the `maxStreamBytes` init config allows.
+    BodyTooLarge(String),
+    /// The request matches none of the `allowedRoutes` of its provider in the init config.
+    RouteNotAllowed(String),
+}
+
+#[derive(Serialize)]
+struct L8ErrorObject<'a> {
+    name: &'static str,
+    code: &'static str,
+    message: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<u16>,
+}
+
+impl L8Error {
+    pub fn name(&self) -> &'static str {
+        match self {
+            L8Error::InvalidRequest(_) => "InvalidRequest",
+            L8Error::InvalidMethod(_) => "InvalidMethod",
+            L8Error::NetworkError(_) => "NetworkError",
+            L8Error::ProxyError { .. } => "ProxyError",
+            L8Error::CryptoError(_) => "CryptoError",
+            L8Error::AbortError(_) => "AbortError",
+            L8Error::TimeoutError(_) => "TimeoutError",
+            L8Error::NotInitialized(_) => "NotInitialized",
+            L8Error::CertPinMismatch(_) => "CertPinMismatch",
+            L8Error::BodyTooLarge(_) => "BodyTooLarge",
+            L8Error::RouteNotAllowed(_) => "RouteNotAllowed",
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            L8Error::InvalidRequest(_) => "L8_INVALID_REQUEST",
+            L8Error::InvalidMethod(_) => "L8_INVALID_METHOD",
+            L8Error::NetworkError(_) => "L8_NETWORK_ERROR",
+            L8Error::ProxyError { .. } => "L8_PROXY_ERROR",
+            L8Error::CryptoError(_) => "L8_CRYPTO_ERROR",
+            L8Error::AbortError(_) => "L8_ABORT_ERROR",
+            L8Error::TimeoutError(_) => "L8_TIMEOUT_ERROR",
+            L8Error::NotInitialized(_) => "L8_NOT_INITIALIZED",
+            L8Error::CertPinMismatch(_) => "L8_CERT_PIN_MISMATCH",
+            L8Error::BodyTooLarge(_) => "L8_BODY_TOO_LARGE",
+            L8Error::RouteNotAllowed(_) => "L8_ROUTE_NOT_ALLOWED",
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            L8Error::InvalidRequest(message)
+            | L8Error::InvalidMethod(message)
+            | L8Error::NetworkError(message)
+            | L8Error::ProxyError { message, .. }
+            | L8Error::CryptoError(message)
+            | L8Error::AbortError(message)
+            | L8Error::TimeoutError(message)
+            | L8Error::NotInitialized(message)
+            | L8Error::CertPinMismatch(message)
+            | L8Error::BodyTooLarge(message)
+            | L8Error::RouteNotAllowed(message) => message,
+        }
+    }
+}
+
+/// Whether the value already has the shape of an `L8Error` converted to JS, a string `name` and `code`. Other errors
+/// (e.g. a `TypeError` of the browser) are to be wrapped in one.
+pub(crate) fn is_l8_error(value: &JsValue) -> bool {
+    let field = |name: &str| {
+        js_sys::Reflect::get(value, &name.into())
+            .ok()
+            .and_then(|field| field.as_string())
+    };
+
+    value.is_object()
+        && field("name").is_some()
+        && field("code").is_some_and(|code| code.starts_with("L8_"))
+}
+
+impl std::fmt::Display for L8Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.name(), self.message())
+    }
+}
+
+impl From<L8Error> for JsValue {
+    fn from(err: L8Error) -> Self {
+        let status = match &err {
+            L8Error::ProxyError { status, .. } => Some(*status),
+            _ => None,
+        };
+
+        let object = L8ErrorObject {
+            name: err.name(),
+            code: err.code(),
+            message: err.message(),
+            status,
+        };
+
+        // serializing a struct of strings can't fail, the message is still kept if it ever does
+        serde_wasm_bindgen::to_value(&object)
+            .unwrap_or_else(|_| JsValue::from_str(&err.to_string()))
+    }
+}
@@ -1,18 +1,36 @@
 use {
+    crate::{
+        types::{request::L8RequestObject, response::L8ResponseObject},
+        utils,
+    },
     bytes::Bytes,
     hyper::{HeaderMap, StatusCode},
-    ntor::common::InitSessionMessage,
-    reqwest::{Error, RequestBuilder, Response},
+    ntor::{
+        common::{EncryptedMessage, InitSessionMessage, NTorParty},
+        server::NTorServer,
+    },
+    reqwest::{Error, Request, RequestBuilder, Response},
     serde::{Deserialize, de::DeserializeOwned},
     serde_json::json,
+    std::{
+        cell::{Cell, RefCell},
+        collections::VecDeque,
+        rc::Rc,
+    },
     wasm_bindgen::UnwrapThrowExt,
 };
 
-/// Represents the response from an HTTP call, which can either be a `reqwest::Response` or raw data.
+/// The identity of the NTor server behind the mocks, its secret being `MOCK_SERVER_SECRET` repeated 16 times.
+const MOCK_SERVER_ID: &str = "server123";
+const MOCK_SERVER_SECRET: [u8; 2] = [1, 2];
+
+/// Represents the response from an HTTP call, which can either be a `reqwest::Response`, raw data answered with a
+/// `200`, or a mocked response with its own status and headers.
 #[derive(Debug)]
 pub enum HttpCallerResponse {
     Reqwest(Response),
     Raw(Vec<u8>),
+    Mock(hyper::Response<Vec<u8>>),
 }
 
 /// A trait that defines the behavior of an HTTP caller, allowing for different implementations
@@ -34,7 +52,12 @@ impl HttpCaller for ActualHttpCaller {
     }
 }
 
-/// A mock implementation of `HttpCaller` for testing purposes, which returns a predefined response.
+/// A mock implementation of `HttpCaller` for testing purposes, which returns a predefined response: a `200` without
+/// headers, so that it can stand in for the proxy wherever `fetch_with` reads its responses.
+///
+/// With `init` set it answers the `init-tunnel` handshake, agreeing on every encoding of the request's
+/// `x-l8-accept-encoding`; a non-empty `data` is then a JSON object whose fields override the ones of the
+/// generated handshake response (e.g. `{"jwt1": ""}`).
 #[derive(Clone)]
 pub struct MockHttpCaller {
     pub data: Vec<u8>,
@@ -45,60 +68,265 @@ impl HttpCaller for MockHttpCaller {
     async fn send(self, req_builder: RequestBuilder) -> Result<HttpCallerResponse, Error> {
         if self.init {
             let req = req_builder.build()?;
-            let pub_key: [u8; 32] = {
-                #[derive(Deserialize)]
-                struct ExpectedRequest {
-                    public_key: Vec<u8>,
-                }
-
-                let json_body = serde_json::from_slice::<ExpectedRequest>(
-                    req.body()
-                        .expect_throw("Request body should be set")
-                        .as_bytes()
-                        .expect_throw("we expect the body to be bytes"),
-                )
-                .expect_throw("Failed to deserialize request body to ExpectedRequest struct");
-
-                json_body
-                    .public_key
+            let mut ntor_server = NTorServer::new_with_secret(
+                MOCK_SERVER_ID.to_string(),
+                MOCK_SERVER_SECRET
+                    .repeat(16)
+                    .as_slice()
                     .try_into()
-                    .expect_throw("Failed to convert to [u8; 32]")
-            };
+                    .expect_throw("Failed to convert to [u8; 32]"),
+            );
+
+            return Ok(HttpCallerResponse::Mock(hyper::Response::new(
+                mock_init_tunnel(&req, &mut ntor_server, &self.data),
+            )));
+        }
 
-            let server_id = "server123".to_string();
-            let ntor_secret = [1, 2]
-                .repeat(16)
-                .as_slice()
-                .try_into()
-                .expect_throw("Failed to convert to [u8; 32]");
+        Ok(HttpCallerResponse::Mock(hyper::Response::new(self.data)))
+    }
+}
+
+/// A mock of the forward proxy for the full round trip of a request. It answers the `init-tunnel` handshake like
+/// `MockHttpCaller` and keeps the NTor session, so that the requests sent to `/proxy` are decrypted, recorded and
+/// answered with `response`, encrypted.
+///
+/// With an error `status` the proxy fails instead, answering with `headers` and the body of `response` in plain.
+///
+/// The clones of a mock share their session, the requests they received and the failures scheduled with
+/// `fail_requests` and `fail_handshakes`, so a clone handed over to `fetch_with` can be inspected afterwards.
+#[derive(Clone)]
+pub struct MockProxy {
+    pub response: L8ResponseObject,
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    /// Encrypted and sent in place of `response` when set, e.g. a payload that is not a response.
+    pub raw_response: Option<Vec<u8>>,
+    ntor_server: Rc<RefCell<NTorServer>>,
+    received: Rc<RefCell<Vec<L8RequestObject>>>,
+    clients: Rc<RefCell<Vec<String>>>,
+    handshakes: Rc<Cell<u32>>,
+    failing_handshakes: Rc<Cell<u32>>,
+    failing_requests: Rc<RefCell<VecDeque<StatusCode>>>,
+    on_request: Rc<RefCell<Option<Box<dyn Fn(&L8RequestObject)>>>>,
+    latency: Rc<Cell<i32>>,
+}
 
-            let mut ntor_server =
-                ntor::server::NTorServer::new_with_secret(server_id.clone(), ntor_secret);
+impl MockProxy {
+    pub fn new(response: L8ResponseObject) -> Self {
+        MockProxy {
+            response,
+            status: StatusCode::OK,
+            headers: HeaderMap::new(),
+            raw_response: None,
+            ntor_server: Rc::new(RefCell::new(NTorServer::new_with_secret(
+                MOCK_SERVER_ID.to_string(),
+                MOCK_SERVER_SECRET
+                    .repeat(16)
+                    .as_slice()
+                    .try_into()
+                    .expect_throw("Failed to convert to [u8; 32]"),
+            ))),
+            received: Rc::new(RefCell::new(Vec::new())),
+            clients: Rc::new(RefCell::new(Vec::new())),
+            handshakes: Rc::new(Cell::new(0)),
+            failing_handshakes: Rc::new(Cell::new(0)),
+            failing_requests: Rc::new(RefCell::new(VecDeque::new())),
+            on_request: Rc::new(RefCell::new(None)),
+            latency: Rc::new(Cell::new(0)),
+        }
+    }
 
-            let init_session_response = {
-                // Client initializes session with the server
-                let init_session_msg = InitSessionMessage::from(pub_key.to_vec());
-                ntor_server.accept_init_session_request(&init_session_msg)
-            };
+    /// The fingerprint of the shared secret of the latest handshake, computed like the interceptor does on its side.
+    pub fn shared_secret_fingerprint(&self) -> Option<String> {
+        self.ntor_server
+            .borrow()
+            .get_shared_secret()
+            .map(|secret| utils::secret_fingerprint(&secret))
+    }
 
-            let cert = ntor_server.get_certificate();
+    /// The decrypted requests the proxy received, oldest first.
+    pub fn received(&self) -> Vec<L8RequestObject> {
+        self.received.borrow().clone()
+    }
 
-            let response = json!({
-                "ephemeral_public_key": init_session_response.public_key(),
-                "t_b_hash": init_session_response.t_b_hash(),
-                "public_key": cert.public_key(),
-                "server_id": server_id,
-                "jwt1": "test_jwt1",
-                "jwt2": "test_jwt2",
-            });
+    /// The `Debug` of the reqwest client behind every call the proxy received, handshakes included, oldest first.
+    /// Clients built with distinct default headers tell apart this way.
+    pub fn clients(&self) -> Vec<String> {
+        self.clients.borrow().clone()
+    }
+
+    /// The number of `init-tunnel` handshakes the proxy was sent, the failed ones included.
+    pub fn handshakes(&self) -> u32 {
+        self.handshakes.get()
+    }
+
+    /// Fails the next `count` handshakes with a transport error, like an unreachable proxy.
+    pub fn fail_handshakes(&self, count: u32) {
+        self.failing_handshakes.set(count);
+    }
+
+    /// Answers the next requests to `/proxy` with these error statuses, one per request, before `status` applies again.
+    pub fn fail_requests(&self, statuses: &[u16]) {
+        self.failing_requests.borrow_mut().extend(
+            statuses
+                .iter()
+                .map(|status| StatusCode::from_u16(*status).expect_throw("a valid status code")),
+        );
+    }
+
+    /// Delays every answer, the handshakes included, by `ms` milliseconds, like a distant proxy.
+    pub fn set_latency(&self, ms: i32) {
+        self.latency.set(ms);
+    }
+
+    /// Calls `hook` with every decrypted request the proxy receives, before it is answered.
+    pub fn on_request(&self, hook: impl Fn(&L8RequestObject) + 'static) {
+        *self.on_request.borrow_mut() = Some(Box::new(hook));
+    }
+}
+
+/// A transport error, as reqwest reports an unreachable server.
+fn mock_transport_error() -> Error {
+    reqwest::Client::new()
+        .get("http://[unreachable")
+        .build()
+        .expect_err("we expect the invalid url to fail the request")
+}
 
-            return Ok(HttpCallerResponse::Raw(
-                serde_json::to_vec(&response).expect_throw("Failed to serialize response to JSON"),
-            ));
+impl HttpCaller for MockProxy {
+    async fn send(self, req_builder: RequestBuilder) -> Result<HttpCallerResponse, Error> {
+        let (client, req) = req_builder.build_split();
+        let req = req?;
+        self.clients.borrow_mut().push(format!("{:?}", client));
+        // before borrowing the session, concurrent requests share it
+        if self.latency.get() > 0 {
+            utils::sleep(self.latency.get()).await;
+        }
+
+        let mut ntor_server = self.ntor_server.borrow_mut();
+        if !req.url().path().ends_with("/proxy") {
+            self.handshakes.set(self.handshakes.get() + 1);
+            if self.failing_handshakes.get() > 0 {
+                self.failing_handshakes
+                    .set(self.failing_handshakes.get() - 1);
+                return Err(mock_transport_error());
+            }
+
+            return Ok(HttpCallerResponse::Raw(mock_init_tunnel(
+                &req,
+                &mut ntor_server,
+                &[],
+            )));
+        }
+
+        let (encrypted, _) = bincode::decode_from_slice::<EncryptedMessage, _>(
+            req.body()
+                .and_then(|body| body.as_bytes())
+                .expect_throw("we expect the proxied request to have a body"),
+            bincode::config::standard(),
+        )
+        .expect_throw("Failed to deserialize the encrypted request");
+        let decrypted = ntor_server
+            .wasm_decrypt(encrypted.nonce.to_vec(), encrypted.data)
+            .map_err(|e| e.to_string())
+            .expect_throw("Failed to decrypt the proxied request");
+        let request: L8RequestObject = serde_json::from_slice(&decrypted)
+            .expect_throw("Failed to deserialize the proxied request");
+        if let Some(hook) = self.on_request.borrow().as_ref() {
+            hook(&request);
+        }
+        self.received.borrow_mut().push(request);
+
+        let status = self
+            .failing_requests
+            .borrow_mut()
+            .pop_front()
+            .unwrap_or(self.status);
+        let body = if status.is_client_error() || status.is_server_error() {
+            self.response.body.clone()
+        } else {
+            let (nonce, data) = ntor_server
+                .wasm_encrypt(self.raw_response.clone().unwrap_or_else(|| {
+                    serde_json::to_vec(&self.response)
+                        .expect_throw("Failed to serialize the mock response")
+                }))
+                .map_err(|e| e.to_string())
+                .expect_throw("Failed to encrypt the mock response");
+            bincode::encode_to_vec(
+                &EncryptedMessage {
+                    nonce: nonce
+                        .try_into()
+                        .expect_throw("Failed to convert nonce to [u8; 12]"),
+                    data,
+                },
+                bincode::config::standard(),
+            )
+            .expect_throw("Failed to serialize the encrypted response")
+        };
+
+        let mut response = hyper::Response::new(body);
+        *response.status_mut() = status;
+        *response.headers_mut() = self.headers.clone();
+        Ok(HttpCallerResponse::Mock(response))
+    }
+}
+
+/// Answers the `init-tunnel` handshake of `req` with `ntor_server`, agreeing on every encoding of the request's
+/// `x-l8-accept-encoding`. A non-empty `overrides` is a JSON object whose fields override the ones of the response.
+fn mock_init_tunnel(req: &Request, ntor_server: &mut NTorServer, overrides: &[u8]) -> Vec<u8> {
+    let pub_key: [u8; 32] = {
+        #[derive(Deserialize)]
+        struct ExpectedRequest {
+            public_key: Vec<u8>,
         }
 
-        Ok(HttpCallerResponse::Raw(self.data))
+        let json_body = serde_json::from_slice::<ExpectedRequest>(
+            req.body()
+                .expect_throw("Request body should be set")
+                .as_bytes()
+                .expect_throw("we expect the body to be bytes"),
+        )
+        .expect_throw("Failed to deserialize request body to ExpectedRequest struct");
+
+        json_body
+            .public_key
+            .try_into()
+            .expect_throw("Failed to convert to [u8; 32]")
+    };
+
+    let init_session_response = {
+        // Client initializes session with the server
+        let init_session_msg = InitSessionMessage::from(pub_key.to_vec());
+        ntor_server.accept_init_session_request(&init_session_msg)
+    };
+
+    let cert = ntor_server.get_certificate();
+    let accept_encoding = req
+        .headers()
+        .get("x-l8-accept-encoding")
+        .and_then(|val| val.to_str().ok())
+        .map(str::to_string);
+
+    let mut response = json!({
+        "ephemeral_public_key": init_session_response.public_key(),
+        "t_b_hash": init_session_response.t_b_hash(),
+        "public_key": cert.public_key(),
+        "server_id": MOCK_SERVER_ID,
+        "jwt1": "test_jwt1",
+        "jwt2": "test_jwt2",
+        "accept_encoding": accept_encoding,
+    });
+
+    if !overrides.is_empty() {
+        let overrides =
+            serde_json::from_slice::<serde_json::Map<String, serde_json::Value>>(overrides)
+                .expect_throw("Mock init overrides should be a JSON object");
+        for (key, value) in overrides {
+            response[key] = value;
+        }
     }
+
+    serde_json::to_vec(&response).expect_throw("Failed to serialize response to JSON")
 }
 
 impl HttpCallerResponse {
@@ -107,6 +335,7 @@ impl HttpCallerResponse {
         match self {
             HttpCallerResponse::Reqwest(response) => response.status(),
             HttpCallerResponse::Raw(_) => StatusCode::OK,
+            HttpCallerResponse::Mock(response) => response.status(),
         }
     }
 
@@ -114,6 +343,7 @@ impl HttpCallerResponse {
     pub fn headers(&self) -> &HeaderMap {
         match self {
             HttpCallerResponse::Reqwest(response) => response.headers(),
+            HttpCallerResponse::Mock(response) => response.headers(),
             HttpCallerResponse::Raw(_) => unimplemented!("not implemented for tests"),
         }
     }
@@ -122,6 +352,7 @@ impl HttpCallerResponse {
     pub fn headers_mut(&mut self) -> &mut HeaderMap {
         match self {
             HttpCallerResponse::Reqwest(response) => response.headers_mut(),
+            HttpCallerResponse::Mock(response) => response.headers_mut(),
             HttpCallerResponse::Raw(_) => unimplemented!("not implemented for tests"),
         }
     }
@@ -131,6 +362,7 @@ impl HttpCallerResponse {
         match self {
             HttpCallerResponse::Reqwest(response) => response.content_length(),
             HttpCallerResponse::Raw(data) => Some(data.len() as u64),
+            HttpCallerResponse::Mock(response) => Some(response.body().len() as u64),
         }
     }
 
@@ -138,7 +370,9 @@ impl HttpCallerResponse {
     pub fn url(&self) -> &url::Url {
         match self {
             HttpCallerResponse::Reqwest(response) => response.url(),
-            HttpCallerResponse::Raw(_) => unimplemented!("not implemented for tests"),
+            HttpCallerResponse::Raw(_) | HttpCallerResponse::Mock(_) => {
+                unimplemented!("not implemented for tests")
+            }
         }
     }
 
@@ -146,7 +380,9 @@ impl HttpCallerResponse {
     pub async fn json<T: DeserializeOwned>(self) -> reqwest::Result<T> {
         match self {
             HttpCallerResponse::Reqwest(response) => response.json().await,
-            HttpCallerResponse::Raw(_) => unimplemented!("not implemented for tests"),
+            HttpCallerResponse::Raw(_) | HttpCallerResponse::Mock(_) => {
+                unimplemented!("not implemented for tests")
+            }
         }
     }
 
@@ -154,7 +390,9 @@ impl HttpCallerResponse {
     pub async fn text(self) -> reqwest::Result<String> {
         match self {
             HttpCallerResponse::Reqwest(response) => response.text().await,
-            HttpCallerResponse::Raw(_) => unimplemented!("not implemented for tests"),
+            HttpCallerResponse::Raw(_) | HttpCallerResponse::Mock(_) => {
+                unimplemented!("not implemented for tests")
+            }
         }
     }
 
@@ -163,6 +401,7 @@ impl HttpCallerResponse {
         match self {
             HttpCallerResponse::Reqwest(response) => response.bytes().await,
             HttpCallerResponse::Raw(data) => Ok(data.clone().into()),
+            HttpCallerResponse::Mock(response) => Ok(response.into_body().into()),
         }
     }
 
@@ -174,6 +413,7 @@ impl HttpCallerResponse {
                 Ok(HttpCallerResponse::Reqwest(response))
             }
             HttpCallerResponse::Raw(data) => Ok(HttpCallerResponse::Raw(data)),
+            HttpCallerResponse::Mock(response) => Ok(HttpCallerResponse::Mock(response)),
         }
     }
 
@@ -184,7 +424,7 @@ impl HttpCallerResponse {
                 response.error_for_status_ref()?;
                 Ok(self)
             }
-            HttpCallerResponse::Raw(_) => Ok(self),
+            HttpCallerResponse::Raw(_) | HttpCallerResponse::Mock(_) => Ok(self),
         }
     }
 }
@@ -0,0 +1,57 @@
+use serde::Deserialize;
+use wasm_bindgen::prelude::wasm_bindgen;
+
+use crate::storage::InMemoryCache;
+
+/// The verbosity of the interceptor's logging, each level including the ones before it.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel {
+    Off,
+    #[default]
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    /// The level implied by the legacy `dev_flag` argument of `initEncryptedTunnel`.
+    pub fn from_dev_flag(dev_flag: Option<bool>) -> Self {
+        match dev_flag {
+            Some(true) => LogLevel::Debug,
+            _ => LogLevel::Error,
+        }
+    }
+
+    /// The lowercase name of the level, as accepted by the `logLevel` config and passed to the `setLogger` callback.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LogLevel::Off => "off",
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+        }
+    }
+
+    /// Whether a message of the given level is logged when this is the configured level.
+    pub fn allows(self, level: LogLevel) -> bool {
+        level != LogLevel::Off && level <= self
+    }
+}
+
+/// Routes the interceptor's logs to `cb` instead of the console. It is called as `cb(level, message)` for every
+/// message the log level lets through, `level` being one of `"error"`, `"warn"`, `"info"` or `"debug"`.
+/// Passing `null` or `undefined` removes the callback, the logs go to the console again.
+#[wasm_bindgen(js_name = "setLogger")]
+pub fn set_logger(cb: Option<js_sys::Function>) {
+    InMemoryCache::set_logger(cb);
+}
+
+/// Turns the dev mode off again, restoring the default `error` log level. The tunnels and the rest of the
+/// configuration are left untouched.
+#[wasm_bindgen(js_name = "resetDevFlag")]
+pub fn reset_dev_flag() {
+    InMemoryCache::set_log_level(LogLevel::default());
+}
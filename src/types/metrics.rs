@@ -0,0 +1,188 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use wasm_bindgen::prelude::*;
+
+use crate::constants::IDB_REQUEST_LOG_STORE;
+use crate::indexeddb;
+use crate::storage::InMemoryCache;
+use crate::types::log_level::LogLevel;
+
+/// Timings of the crypto path for a single tunneled request. These are only recorded when the
+/// dev mode is enabled, to profile the per-request encryption cost against the payload size.
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestMetrics {
+    pub uri: String,
+    /// Size of the serialized request before encryption.
+    pub request_bytes: usize,
+    pub encrypt_ms: f64,
+    /// Size of the encrypted response as received from the proxy.
+    pub response_bytes: usize,
+    pub decrypt_ms: f64,
+}
+
+/// The stages of a `fetch` call, passed to the `onTiming` callback of the init config once the response is ready.
+/// Stages that ran several times (e.g. after a reinitialization) are summed up.
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestTiming {
+    /// The base url of the service provider.
+    pub provider: String,
+    /// Time spent waiting for the tunnel to open, including the reinitialization handshakes.
+    pub handshake_ms: f64,
+    /// Time spent encrypting, sending and receiving the proxied request, decryption excluded.
+    pub request_ms: f64,
+    pub decrypt_ms: f64,
+    pub total_ms: f64,
+    #[serde(skip)]
+    started_at: f64,
+}
+
+impl RequestTiming {
+    pub fn start(provider: &str) -> Self {
+        RequestTiming {
+            provider: provider.to_string(),
+            started_at: js_sys::Date::now(),
+            ..Default::default()
+        }
+    }
+
+    /// Completes the total time and hands the timing over to the `onTiming` callback, if any.
+    pub fn report(mut self) {
+        self.total_ms = js_sys::Date::now() - self.started_at;
+
+        let Some(on_timing) = InMemoryCache::get_on_timing() else {
+            return;
+        };
+
+        match serde_wasm_bindgen::to_value(&self) {
+            // the callback is the embedder's concern, it failing must not fail the request
+            Ok(timing) => _ = on_timing.call1(&JsValue::NULL, &timing),
+            Err(e) => InMemoryCache::log(
+                LogLevel::Warn,
+                &format!("Failed to serialize request timing: {}", e).into(),
+            ),
+        }
+    }
+}
+
+/// The plaintext of a tunneled request or response, passed to the `onInspect` callback of the init config in dev mode.
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum InspectEvent<'a> {
+    /// A request right before it is encrypted.
+    Request {
+        method: &'a str,
+        uri: &'a str,
+        headers: &'a HashMap<String, serde_json::Value>,
+        #[serde(rename = "bodyLen")]
+        body_len: usize,
+    },
+    /// A response right after it is decrypted.
+    Response {
+        status: u16,
+        headers: &'a HashMap<String, serde_json::Value>,
+        #[serde(rename = "bodyLen")]
+        body_len: usize,
+    },
+}
+
+impl InspectEvent<'_> {
+    /// Hands the event over to the `onInspect` callback, if any. Nothing is reported outside of the dev mode.
+    pub fn report(&self) {
+        let Some(on_inspect) = InMemoryCache::get_on_inspect() else {
+            return;
+        };
+
+        // header values are JSON values, only the JSON compatible serializer turns maps into plain objects
+        match self.serialize(&serde_wasm_bindgen::Serializer::json_compatible()) {
+            // the callback is the embedder's concern, it failing must not fail the request
+            Ok(event) => _ = on_inspect.call1(&JsValue::NULL, &event),
+            Err(e) => InMemoryCache::log(
+                LogLevel::Warn,
+                &format!("Failed to serialize inspect event: {}", e).into(),
+            ),
+        }
+    }
+}
+
+/// The metadata of a `fetch` call, persisted to IndexedDB to reproduce field bugs when both the dev mode and the
+/// `persistRequestLogs` init option are enabled. Headers, bodies and query strings are never recorded.
+#[derive(Serialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct RequestLogEntry {
+    pub timestamp: f64,
+    pub method: String,
+    /// The request url stripped of its credentials, query string and fragment.
+    pub url: String,
+    /// The status of the response, absent when the request failed.
+    pub status: Option<u16>,
+    /// The name of the error the request failed with, e.g. `NetworkError`.
+    pub error: Option<String>,
+    pub duration_ms: f64,
+}
+
+impl RequestLogEntry {
+    pub fn start(url: &str) -> Self {
+        let url = match url::Url::parse(url) {
+            Ok(mut url) => {
+                url.set_query(None);
+                url.set_fragment(None);
+                _ = url.set_username("");
+                _ = url.set_password(None);
+                url.to_string()
+            }
+            Err(_) => String::new(),
+        };
+
+        RequestLogEntry {
+            timestamp: js_sys::Date::now(),
+            url,
+            ..Default::default()
+        }
+    }
+
+    /// Records the outcome of the request, its status or the error it failed with, and appends the entry to the
+    /// persisted logs, keeping the `capacity` most recent ones.
+    pub async fn persist(mut self, result: Result<u16, &JsValue>, capacity: u32) {
+        self.duration_ms = js_sys::Date::now() - self.timestamp;
+        match result {
+            Ok(status) => self.status = Some(status),
+            Err(err) => {
+                self.error = js_sys::Reflect::get(err, &"name".into())
+                    .ok()
+                    .and_then(|name| name.as_string())
+                    .or_else(|| Some("Error".to_string()))
+            }
+        }
+
+        let persisted = match serde_wasm_bindgen::to_value(&self) {
+            Ok(entry) => indexeddb::idb_append(IDB_REQUEST_LOG_STORE, &entry, capacity)
+                .await
+                .map(drop),
+            Err(e) => Err(JsValue::from_str(&format!(
+                "Failed to serialize request log: {}",
+                e
+            ))),
+        };
+
+        // a debugging aid, it failing must not fail the request
+        if let Err(err) = persisted {
+            InMemoryCache::log(LogLevel::Warn, &err);
+        }
+    }
+}
+
+/// Returns the request logs persisted with the `persistRequestLogs` init option, oldest first.
+#[wasm_bindgen(js_name = "exportLogs")]
+pub async fn export_logs() -> Result<JsValue, JsValue> {
+    Ok(indexeddb::idb_get_all(IDB_REQUEST_LOG_STORE).await?.into())
+}
+
+/// Returns the metrics of the most recent tunneled requests, oldest first.
+/// The list stays empty unless the dev mode is enabled.
+#[wasm_bindgen(js_name = "getRequestMetrics")]
+pub fn get_request_metrics() -> Result<JsValue, JsValue> {
+    serde_wasm_bindgen::to_value(&InMemoryCache::get_request_metrics())
+        .map_err(|e| JsValue::from_str(&format!("Failed to serialize request metrics: {}", e)))
+}
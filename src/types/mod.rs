@@ -1,5 +1,9 @@
+pub mod config;
+pub mod error;
 pub mod http_caller;
+pub mod log_level;
+pub mod metrics;
 pub mod network_state;
 pub mod request;
-mod response;
-pub(crate) mod service_provider;
+pub mod response;
+pub mod service_provider;
@@ -1,6 +1,8 @@
 use crate::init_tunnel::InitTunnelResult;
+use crate::types::{error::L8Error, response::L8ResponseObject};
+use crate::utils::CompressorVariant;
 use bytes::Bytes;
-use ntor::common::{EncryptedMessage, NTorParty};
+use serde::Serialize;
 use wasm_bindgen::prelude::*;
 
 /// Represents the current state of the network connection for a service provider.
@@ -11,65 +13,89 @@ pub(crate) enum NetworkState {
     /// The network is open and ready for use.
     OPEN(NetworkStateOpen),
     /// An error occurred while trying to establish the network connection.
-    ERRORED(JsValue),
+    ERRORED(NetworkStateErrored),
+}
+
+impl NetworkState {
+    /// The name of the state, as passed to the `onTunnelStateChange` listeners.
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            NetworkState::CONNECTING => "CONNECTING",
+            NetworkState::OPEN(_) => "OPEN",
+            NetworkState::ERRORED(_) => "ERRORED",
+        }
+    }
+}
+
+/// This is the state of the network connection for a service provider whose key exchange failed.
+/// It keeps what is needed to retry the initialization later.
+#[derive(Debug, Clone)]
+pub(crate) struct NetworkStateErrored {
+    pub error: JsValue,
+    pub forward_proxy_url: String,
+    pub http_client: reqwest::Client,
+    /// `Date.now()` timestamp of the failure
+    pub errored_at: f64,
 }
 
 /// This is the state of the network connection for a service provider when it has
 /// completed key exchange and is ready to be used.
 #[derive(Debug, Clone)]
-pub(crate) struct NetworkStateOpen {
+pub struct NetworkStateOpen {
     pub http_client: reqwest::Client,
     pub init_tunnel_result: InitTunnelResult,
     pub forward_proxy_url: String,
 }
 
+/// The schemes of both legs of a provider's tunnel, tracked apart: the browser connects to the forward proxy, and the
+/// proxy to the backend. A `https` proxy may terminate TLS in front of a `http` backend, only the proxy leg is subject
+/// to the mixed content rules of the page (see `utils::check_mixed_content`).
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ProviderSchemes {
+    pub proxy: String,
+    pub backend: String,
+}
+
+impl ProviderSchemes {
+    pub fn new(forward_proxy_url: &str, backend_url: &str) -> Result<Self, JsValue> {
+        let scheme = |url: &str| {
+            url::Url::parse(url)
+                .map(|url| url.scheme().to_string())
+                .map_err(|e| L8Error::InvalidRequest(format!("Invalid URL {}: {}", url, e)))
+        };
+
+        Ok(ProviderSchemes {
+            proxy: scheme(forward_proxy_url)?,
+            backend: scheme(backend_url)?,
+        })
+    }
+}
+
 // This enum is used to represent the response from the network state.
 pub enum NetworkStateResponse {
     // This is an error in response to the unexpected response from the proxy server.
     ProxyError(JsValue),
-    // This is a successful response from the proxy server.
-    ProviderResponse(web_sys::Response),
+    // This is a successful response from the proxy server, decrypted.
+    ProviderResponse(L8ResponseObject),
     // This is an indicator that we are reinitializing the connection
     Reinitialize,
 }
 
 impl NetworkStateOpen {
-    pub fn ntor_encrypt(&self, data: Vec<u8>) -> Result<Vec<u8>, JsValue> {
-        let (nonce, encrypted) = self
-            .init_tunnel_result
-            .client
-            .wasm_encrypt(data)
-            .map_err(|e| JsValue::from_str(&format!("Failed to encrypt data: {}", e)))?;
-
-        let nonce = TryInto::<[u8; 12]>::try_into(nonce)
-            .map_err(|_e| JsValue::from_str("Failed to convert nonce to array of 12 bytes"))?;
-
-        let msg = bincode::encode_to_vec(
-            &EncryptedMessage {
-                nonce,
-                data: encrypted,
-            },
-            bincode::config::standard(),
-        )
-        .map_err(|e| JsValue::from_str(&format!("Failed to serialize encrypted message: {}", e)))?;
+    pub fn new(init_tunnel_result: InitTunnelResult, forward_proxy_url: String) -> Self {
+        NetworkStateOpen {
+            http_client: reqwest::Client::new(),
+            init_tunnel_result,
+            forward_proxy_url,
+        }
+    }
 
-        Ok(msg)
+    pub fn ntor_encrypt(&self, data: Vec<u8>) -> Result<Vec<u8>, JsValue> {
+        self.init_tunnel_result.ntor_encrypt(data)
     }
 
     pub fn ntor_decrypt(&self, data: &Bytes) -> Result<Vec<u8>, JsValue> {
-        let encrypted_data =
-            bincode::decode_from_slice::<EncryptedMessage, _>(data, bincode::config::standard())
-                .map_err(|e| {
-                    JsValue::from_str(&format!("Failed to deserialize encrypted message: {}", e))
-                })?;
-
-        let decrypted_response = self
-            .init_tunnel_result
-            .client
-            .wasm_decrypt(encrypted_data.0.nonce.to_vec(), encrypted_data.0.data)
-            .map_err(|e| JsValue::from_str(&format!("Failed to decrypt data: {}", e)))?;
-
-        Ok(decrypted_response)
+        self.init_tunnel_result.ntor_decrypt(data)
     }
 
     pub fn int_rp_jwt(&self) -> String {
@@ -79,4 +105,8 @@ impl NetworkStateOpen {
     pub fn int_fp_jwt(&self) -> String {
         self.init_tunnel_result.int_fp_jwt.clone()
     }
+
+    pub fn accepted_encodings(&self) -> &[CompressorVariant] {
+        self.init_tunnel_result.accepted_encodings()
+    }
 }
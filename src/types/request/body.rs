@@ -1,12 +1,14 @@
-use std::collections::HashMap;
+use crate::types::error::L8Error;
+use crate::utils;
 use wasm_bindgen::{JsCast, JsValue, UnwrapThrowExt};
 use wasm_streams::ReadableStream;
 
 pub enum L8BodyType {
     Bytes(Vec<u8>),
-    Stream(ReadableStream),
-    Params(HashMap<String, String>),
+    Stream(ReadableStream, Option<usize>), // the size of the stream when known
+    Params(Vec<(String, String)>),         // ordered, keys may repeat
     FormData(web_sys::FormData),
+    Object(js_sys::Object), // a plain object, sent as JSON unless the caller set another content type
     #[allow(dead_code)]
     File(web_sys::File),
 }
@@ -17,13 +19,14 @@ impl L8BodyType {
     /// Supported types:
     /// - a string
     /// - ArrayBuffer
-    /// - TypedArray (todo)
+    /// - TypedArray
     /// - DataView
     /// - Blob
     /// - File
     /// - URLSearchParams
     /// - FormData
     /// - ReadableStream
+    /// - a plain object, as JSON (non-standard)
     pub async fn from_jsvalue(body: JsValue) -> Result<Self, JsValue> {
         // a string
         if body.is_string() {
@@ -34,25 +37,16 @@ impl L8BodyType {
             ));
         }
 
-        // ArrayBuffer
-        if let Some(val) = body.dyn_ref::<js_sys::ArrayBuffer>() {
-            let uint8_array = js_sys::Uint8Array::new(val);
-            return Ok(L8BodyType::Bytes(uint8_array.to_vec()));
-        }
-
-        // *TypedArray, todo
-
-        // DataView
-        if let Some(val) = body.dyn_ref::<js_sys::DataView>() {
-            let uint8_array = js_sys::Uint8Array::new(&val.buffer());
-            return Ok(L8BodyType::Bytes(uint8_array.to_vec()));
+        // ArrayBuffer, TypedArray and DataView
+        if let Some(bytes) = utils::buffer_source_to_bytes(&body) {
+            return Ok(L8BodyType::Bytes(bytes));
         }
 
         // Blob
         if let Some(val) = body.dyn_ref::<web_sys::Blob>() {
             let readable_stream = val.stream();
             let body = ReadableStream::from_raw(readable_stream);
-            return Ok(L8BodyType::Stream(body));
+            return Ok(L8BodyType::Stream(body, Some(val.size() as usize)));
         }
 
         // File
@@ -62,12 +56,12 @@ impl L8BodyType {
                 .expect_throw("Expected body to be a web_sys::File");
             let readable_stream = val.stream();
             let body = ReadableStream::from_raw(readable_stream);
-            return Ok(L8BodyType::Stream(body));
+            return Ok(L8BodyType::Stream(body, Some(val.size() as usize)));
         }
 
         // URLSearchParams
         if let Some(val) = body.dyn_ref::<web_sys::UrlSearchParams>() {
-            let mut params = HashMap::new();
+            let mut params = Vec::new();
             for entry in val.entries() {
                 // [key, value] item array
                 let key_value_entry = js_sys::Array::from(
@@ -81,7 +75,7 @@ impl L8BodyType {
                     .get(1)
                     .as_string()
                     .expect_throw("Expected value in URLSearchParams value entry to be a string");
-                params.insert(key, value);
+                params.push((key, value));
             }
             return Ok(L8BodyType::Params(params));
         }
@@ -101,7 +95,18 @@ impl L8BodyType {
                 .dyn_into::<web_sys::ReadableStream>()
                 .expect_throw("Expected body to be a web_sys::ReadableStream");
             let body = ReadableStream::from_raw(readable_stream);
-            return Ok(L8BodyType::Stream(body));
+            return Ok(L8BodyType::Stream(body, None));
+        }
+
+        // A plain object, most likely meant as a JSON body
+        if let Some(val) = body.dyn_ref::<js_sys::Object>() {
+            let prototype = js_sys::Object::get_prototype_of(val);
+            if prototype.is_null()
+                || JsValue::from(prototype)
+                    == JsValue::from(js_sys::Object::get_prototype_of(&js_sys::Object::new()))
+            {
+                return Ok(L8BodyType::Object(val.clone()));
+            }
         }
 
         // Other objects are converted to strings using their toString() method.
@@ -112,9 +117,11 @@ impl L8BodyType {
             return Ok(L8BodyType::Bytes(val.into_bytes()));
         }
 
-        Err(JsValue::from_str(
+        Err(L8Error::InvalidRequest(
             "Invalid body type for fetch. Expected a string, ArrayBuffer, TypedArray, \
-            DataView, Blob, File, URLSearchParams, FormData, or ReadableStream.",
-        ))
+            DataView, Blob, File, URLSearchParams, FormData, or ReadableStream."
+                .to_string(),
+        )
+        .into())
     }
 }
@@ -1,18 +1,190 @@
 mod body;
 mod mode_and_policies;
 
+use crate::constants::{
+    CORS_SAFELISTED_CONTENT_TYPES, CORS_SAFELISTED_HEADERS, CORS_SAFELISTED_METHODS,
+    ERROR_BODY_PREVIEW_BYTES, KEEPALIVE_BODY_LIMIT, STANDARD_HTTP_METHODS,
+    STREAM_PREALLOCATION_LIMIT, TUNNEL_ERROR_STATUSES, TUNNEL_EXPIRED_HEADER,
+};
+use crate::fetch;
 use crate::storage::InMemoryCache;
 use crate::types::{
+    error::{L8Error, is_l8_error},
+    http_caller::HttpCaller,
+    log_level::LogLevel,
+    metrics::{InspectEvent, RequestMetrics},
     network_state::{NetworkStateOpen, NetworkStateResponse},
-    response::L8ResponseObject,
+    response::{CachedResponse, L8ResponseObject},
 };
 use crate::utils;
 use body::L8BodyType;
+use bytes::Bytes;
 use mode_and_policies::{L8RequestMode, get_request_referer_policy};
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use wasm_bindgen::{JsCast, JsValue, UnwrapThrowExt};
-use web_sys::{AbortSignal, Request, RequestInit, console};
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{AbortSignal, Request, RequestInit, RequestRedirect};
+
+/// The options object keys understood by `fetch`: the `RequestInit` members and the non-standard Layer8 options.
+const KNOWN_FETCH_OPTIONS: [&str; 27] = [
+    "attributionReporting",
+    "body",
+    "browsingTopics",
+    "cache",
+    "credentials",
+    "destination",
+    "duplex",
+    "headers",
+    "integrity",
+    "isHistoryNavigation",
+    "keepalive",
+    "method",
+    "mode",
+    "priority",
+    "redirect",
+    "referrer",
+    "referrerPolicy",
+    "signal",
+    "window",
+    // Layer8 options
+    "compressBody",
+    "l8Meta",
+    "noRetry",
+    "onDownloadProgress",
+    "queueOffline",
+    "rawBody",
+    "sessionKey",
+    "timeout",
+];
+
+/// The answer of the proxy to a tunneled request, received through reqwest or the browser's `fetch`.
+struct ProxyResponse {
+    status: reqwest::StatusCode,
+    /// Whether the proxy marked the response with `TUNNEL_EXPIRED_HEADER`.
+    tunnel_expired: bool,
+    /// The headers of the proxy's answer, those of the `Response` of the request when it is an error response.
+    headers: HashMap<String, serde_json::Value>,
+    /// The encrypted body, or why it could not be read.
+    body: Result<Bytes, String>,
+}
+
+impl ProxyResponse {
+    async fn send(
+        http_caller: impl HttpCaller,
+        req_builder: reqwest::RequestBuilder,
+    ) -> Result<Self, String> {
+        let response = http_caller
+            .send(req_builder)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(ProxyResponse {
+            status: response.status(),
+            tunnel_expired: response.headers().contains_key(TUNNEL_EXPIRED_HEADER),
+            headers: utils::header_map_to_hashmap(response.headers()),
+            body: response.bytes().await.map_err(|e| e.to_string()),
+        })
+    }
+
+    async fn send_keepalive(proxy_url: &str, init: RequestInit) -> Result<Self, String> {
+        let response = JsFuture::from(fetch::native_fetch(&JsValue::from(proxy_url), &init))
+            .await
+            .map_err(|e| format!("{:?}", e))?
+            .unchecked_into::<web_sys::Response>();
+        let status = reqwest::StatusCode::from_u16(response.status()).map_err(|e| e.to_string())?;
+        let tunnel_expired = response
+            .headers()
+            .has(TUNNEL_EXPIRED_HEADER)
+            .unwrap_or_default();
+        let headers = utils::headers_to_reqwest_headers(response.headers().into())
+            .map_err(|e| format!("{:?}", e))?;
+
+        let body = match response.array_buffer() {
+            Ok(buffer) => JsFuture::from(buffer)
+                .await
+                .map(|buffer| Bytes::from(js_sys::Uint8Array::new(&buffer).to_vec()))
+                .map_err(|e| format!("{:?}", e)),
+            Err(e) => Err(format!("{:?}", e)),
+        };
+
+        Ok(ProxyResponse {
+            status,
+            tunnel_expired,
+            headers,
+            body,
+        })
+    }
+
+    /// The error response of the proxy as the response of the request, its body not being encrypted.
+    fn into_plain_response(mut self) -> Result<L8ResponseObject, JsValue> {
+        let body = self
+            .body
+            .map_err(|e| L8Error::NetworkError(format!("Failed to read response body: {}", e)))?;
+
+        // the browser already decoded the body, these described it on the wire
+        self.headers.retain(|name, _| {
+            !name.eq_ignore_ascii_case("content-encoding")
+                && !name.eq_ignore_ascii_case("content-length")
+        });
+
+        Ok(L8ResponseObject {
+            status: self.status.as_u16(),
+            status_text: self
+                .status
+                .canonical_reason()
+                .unwrap_or_default()
+                .to_string(),
+            headers: self.headers,
+            body: body.to_vec(),
+            ok: false,
+            url: String::new(),
+            redirected: false,
+        })
+    }
+}
+
+/// The error of a request to `uri` whose `signal` is aborted: a `TimeoutError` when it was aborted by its `timeout`
+/// (or an `AbortSignal.timeout()`), an `AbortError` otherwise.
+pub(crate) fn abort_error(signal: &AbortSignal, uri: &str) -> JsValue {
+    let timed_out = js_sys::Reflect::get(&signal.reason(), &"name".into())
+        .ok()
+        .and_then(|name| name.as_string())
+        .is_some_and(|name| name == "TimeoutError");
+    if timed_out {
+        return L8Error::TimeoutError(format!("The request to {} timed out", uri)).into();
+    }
+
+    L8Error::AbortError(format!("The request to {} was aborted", uri)).into()
+}
+
+/// Whether the proxy answering `status` failed as a tunnel (an expired tunnel, marked with `TUNNEL_EXPIRED_HEADER`,
+/// or one of the `TUNNEL_ERROR_STATUSES`) rather than handing over an error response of the provider. Only tunnel
+/// errors reinitialize the tunnel.
+pub(crate) fn is_tunnel_error(status: u16, tunnel_expired: bool) -> bool {
+    tunnel_expired || TUNNEL_ERROR_STATUSES.contains(&status)
+}
+
+/// Whether a request that failed on its (1-based) `attempt` should be retried, as decided by the `retryPredicate`
+/// of the init config. `status` is `0` for transport errors. Every failure is retried when there is no predicate,
+/// or when it throws.
+pub(crate) fn should_retry(status: u16, error_kind: &str, attempt: u32) -> bool {
+    let Some(predicate) = InMemoryCache::get_retry_predicate() else {
+        return true;
+    };
+
+    match predicate.call3(
+        &JsValue::NULL,
+        &status.into(),
+        &error_kind.into(),
+        &attempt.into(),
+    ) {
+        Ok(retry) => retry.is_truthy(),
+        Err(err) => {
+            InMemoryCache::log(LogLevel::Warn, &err);
+            true
+        }
+    }
+}
 
 /// A JSON serializable wrapper for a request that can be sent using the Fetch API.
 #[derive(Debug, Clone, Default, Deserialize, Serialize)]
@@ -21,6 +193,14 @@ pub struct L8RequestObject {
     pub method: String,
     pub headers: HashMap<String, serde_json::Value>,
     pub body: Vec<u8>,
+    /// Structured metadata for the proxy (e.g. routing hints) from the `l8Meta` fetch option.
+    /// It travels inside the encrypted payload and never becomes an HTTP header.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub l8_meta: Option<serde_json::Value>,
+    /// The encoding of `body` when it was compressed (see the `compressBody` option), so the proxy can decode it
+    /// before forwarding. The rest of the object is never compressed.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub body_encoding: Option<String>,
 
     // User agent configurations
     #[serde(skip)]
@@ -41,8 +221,34 @@ pub struct L8RequestObject {
     pub mode: Option<L8RequestMode>,
     #[serde(skip)]
     pub redirect: Option<String>,
+    /// The `signal` option, combined with the non-standard `timeout` option (in milliseconds) when set.
     #[serde(skip)]
     pub signal: Option<AbortSignal>,
+    /// Set by the non-standard `noRetry` option, the request fails fast instead of reinitializing the tunnel.
+    #[serde(skip)]
+    pub no_retry: bool,
+    /// Set by the non-standard `rawBody` option, the response body is handed over still encoded (eg. gzip)
+    /// along with its `Content-Encoding` header.
+    #[serde(skip)]
+    pub raw_body: bool,
+    /// Set by the non-standard `compressBody` option, the body is gzipped inside the encrypted payload.
+    #[serde(skip)]
+    pub compress_body: bool,
+    /// Set by the non-standard `sessionKey` option, selects which session of the provider to use when several
+    /// were registered for the same base url.
+    #[serde(skip)]
+    pub session_key: Option<String>,
+    /// Set by the non-standard `queueOffline` option, overrides whether the request waits in the offline queue
+    /// (see the `queueOffline` init config) instead of failing. By default only idempotent requests do.
+    #[serde(skip)]
+    pub queue_offline: Option<bool>,
+    /// Set by the non-standard `onDownloadProgress` option, called with the `DownloadProgress` of the response body.
+    #[serde(skip)]
+    pub on_download_progress: Option<js_sys::Function>,
+    /// Set by `fetch` from `response_cache_key`, the response is then looked up in and stored to the in-memory
+    /// response cache.
+    #[serde(skip)]
+    pub response_cache_key: Option<String>,
 }
 
 impl L8RequestObject {
@@ -52,13 +258,9 @@ impl L8RequestObject {
         resource: JsValue,
         options: Option<RequestInit>,
     ) -> Result<Self, JsValue> {
-        let dev_flag = InMemoryCache::get_dev_flag();
-
         let uri = utils::get_uri(&backend_url)?;
 
-        if dev_flag {
-            console::log_1(&format!("Resource URL: {}", uri).into());
-        }
+        InMemoryCache::log(LogLevel::Debug, &format!("Resource URL: {}", uri).into());
 
         // using the Request object to fetch the resource
         if let Some(req) = resource.dyn_ref::<Request>() {
@@ -81,6 +283,11 @@ impl L8RequestObject {
     }
 
     async fn from_request_options(mut uri: String, options: RequestInit) -> Result<Self, JsValue> {
+        // before the body is read, a mistyped option must not cost the caller its stream body
+        if InMemoryCache::get_strict_options() {
+            Self::reject_unknown_options(&options)?;
+        }
+
         // Using the resource URL and options object to fetch the resource
         let mut req_wrapper = L8RequestObject {
             uri: uri.clone(),
@@ -93,26 +300,40 @@ impl L8RequestObject {
         };
 
         let body = options.get_body();
+        // kept for after the caller's headers are set, the body is consumed below
+        let blob_body = body.dyn_ref::<web_sys::Blob>().cloned();
+        let mut object_body = None;
         if !body.is_undefined() && !body.is_null() {
             let body = L8BodyType::from_jsvalue(body).await.map_err(|e| {
-                JsValue::from_str(&format!(
+                // typed errors (e.g. `BodyTooLarge`) are kept as they are
+                if is_l8_error(&e) {
+                    return e;
+                }
+
+                let message = e.as_string().or_else(|| {
+                    js_sys::Reflect::get(&e, &"message".into())
+                        .ok()
+                        .and_then(|message| message.as_string())
+                });
+                L8Error::InvalidRequest(format!(
                     "Failed to parse request body: {}",
-                    e.as_string().unwrap_or_else(|| "Unknown error".to_string())
+                    message.unwrap_or_else(|| "Unknown error".to_string())
                 ))
+                .into()
             })?;
 
             match body {
                 L8BodyType::Bytes(bytes) => req_wrapper.body = bytes,
 
+                L8BodyType::Object(object) => object_body = Some(object),
+
                 L8BodyType::Params(params) => {
-                    let query = params
-                        .iter()
-                        .map(|(key, value)| format!("{}={}", key, value))
-                        .collect::<Vec<String>>()
-                        .join("&");
+                    let query = utils::encode_query(&params);
 
-                    // reconstruct the uri
-                    uri.push_str(&format!("?{}", query));
+                    // reconstruct the uri, keeping any query string it already had
+                    let separator = if uri.contains('?') { '&' } else { '?' };
+                    uri.push(separator);
+                    uri.push_str(&query);
 
                     req_wrapper.uri = uri.to_string();
                 }
@@ -145,9 +366,14 @@ impl L8RequestObject {
                     req_wrapper.body = uint8_array.to_vec();
                 }
 
-                L8BodyType::Stream(stream) => {
+                L8BodyType::Stream(stream, size_hint) => {
                     // Convert ReadableStream to bytes
-                    let bytes = utils::readable_stream_to_bytes(stream.into_raw()).await?;
+                    let bytes = utils::readable_stream_to_bytes(
+                        stream.into_raw(),
+                        size_hint.or_else(|| Self::content_length_hint(&options.get_headers())),
+                        InMemoryCache::get_max_stream_bytes().map(|max| max as usize),
+                    )
+                    .await?;
                     req_wrapper.body = bytes;
                 }
             }
@@ -159,8 +385,19 @@ impl L8RequestObject {
             req_wrapper.headers.extend(headers);
         }
 
+        if let Some(object) = object_body {
+            req_wrapper.apply_object_body(&object)?;
+        }
+
+        if let Some(blob) = blob_body {
+            req_wrapper.apply_blob_type(&blob);
+            if let Some(file) = blob.dyn_ref::<web_sys::File>() {
+                req_wrapper.apply_file_name(file);
+            }
+        }
+
         // add properties to the request object
-        req_wrapper.add_properties(&options);
+        req_wrapper.add_properties(&options)?;
 
         Ok(req_wrapper)
     }
@@ -179,123 +416,841 @@ impl L8RequestObject {
             // This allows the request to be serialized, encrypted, or processed before transmission.
             // In Rust and WASM, you cannot directly use a JS ReadableStream as a request body;
             // you must read all its chunks and accumulate them into a byte array for further handling.
-            req_wrapper.body = utils::readable_stream_to_bytes(readable_stream)
-                .await
-                .map_err(|e| JsValue::from_str(&format!("Failed to read stream: {:?}", e)))?;
+            req_wrapper.body = utils::readable_stream_to_bytes(
+                readable_stream,
+                Self::content_length_hint(&req.headers().into()),
+                InMemoryCache::get_max_stream_bytes().map(|max| max as usize),
+            )
+            .await?;
         };
 
         req_wrapper.headers = utils::headers_to_reqwest_headers(JsValue::from(req.headers()))?;
         req_wrapper.mode = Some(L8RequestMode::Cors); // Default mode for Request objects
+        req_wrapper.redirect = Some(
+            match req.redirect() {
+                RequestRedirect::Error => "error",
+                RequestRedirect::Manual => "manual",
+                _ => "follow",
+            }
+            .to_string(),
+        );
         Ok(req_wrapper)
     }
 
-    /// Sends the request using the Layer8 network state.
+    /// The `Content-Length` of the caller's headers, the size hint of a stream body. Unlike a blob size it is only
+    /// the caller's word, so no more than `STREAM_PREALLOCATION_LIMIT` of it is used: the wasm memory allocated for
+    /// a bogus length is never given back.
+    pub fn content_length_hint(headers: &JsValue) -> Option<usize> {
+        utils::headers_to_reqwest_headers(headers.clone())
+            .ok()?
+            .into_iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+            .and_then(|(_, value)| value.as_str()?.trim().parse::<usize>().ok())
+            .map(|length| length.min(STREAM_PREALLOCATION_LIMIT))
+    }
+
+    /// The key of the network state (tunnel) this request goes through.
+    pub fn network_state_key(&self, base_url: &str) -> String {
+        utils::session_cache_key(base_url, self.session_key.as_deref())
+    }
+
+    /// The key of the request in the in-memory response cache: its method, network state key and uri, whether the
+    /// body is handed over still encoded (`rawBody`), and a fingerprint of its `Authorization` header so that a
+    /// response is never served to other credentials. Only GET and HEAD requests are cached, and neither requests
+    /// with `cache: "no-store"`, conditional requests, whose caller handles the revalidation itself, nor `Range`
+    /// requests, which a cached full response can't answer.
+    pub fn response_cache_key(&self, network_state_key: &str) -> Option<String> {
+        if !matches!(self.method.as_str(), "GET" | "HEAD") || self.cache == "no-store" {
+            return None;
+        }
+
+        let uncacheable = self.headers.keys().any(|name| {
+            name.eq_ignore_ascii_case("if-none-match")
+                || name.eq_ignore_ascii_case("if-modified-since")
+                || name.eq_ignore_ascii_case("range")
+        });
+        if uncacheable {
+            return None;
+        }
+
+        let mut key = format!("{} {}{}", self.method, network_state_key, self.uri);
+        if self.raw_body {
+            key.push_str(" raw");
+        }
+        let authorization = self
+            .headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("authorization"));
+        if let Some((_, value)) = authorization {
+            key.push_str(" auth:");
+            key.push_str(&utils::secret_fingerprint(value.to_string().as_bytes()));
+        }
+
+        Some(key)
+    }
+
+    /// The response cached for this request under `response_cache_key`, as the `cache` option allows it to be used:
+    /// - `reload` never uses the cache, its response is cached anew
+    /// - `no-cache` always revalidates the cached response, it is never served as is
+    /// - `force-cache` serves the cached response even when stale
+    /// - otherwise the cached response is served while fresh, and revalidated once stale
+    ///
+    /// Returns the response to serve right away, or `None` after adding the `If-None-Match` header revalidating a
+    /// stale response with an `ETag`.
+    pub fn use_cached_response(&mut self) -> Option<L8ResponseObject> {
+        if self.cache == "reload" {
+            return None;
+        }
+
+        let cached = CachedResponse::lookup(self.response_cache_key.as_deref()?, &self.headers)?;
+        let serve = match self.cache.as_str() {
+            "force-cache" => true,
+            "no-cache" => false,
+            _ => cached.is_fresh(js_sys::Date::now()),
+        };
+        if serve {
+            return Some(cached.response);
+        }
+
+        if let Some(etag) = cached.etag {
+            self.headers
+                .insert("If-None-Match".to_string(), etag.into());
+        }
+        None
+    }
+
+    /// Checks the whole request before anything is encrypted or sent, returning the first problem found:
+    /// - the method must be a standard HTTP method or one of the `customMethods` of the init config (`InvalidMethod`)
+    /// - the URI must be an origin-form path (`/path?query`)
+    /// - header names and values must be valid HTTP header names and values
+    /// - GET and HEAD requests can't have a body
+    pub fn validate(&self) -> Result<(), JsValue> {
+        let invalid = |message: String| -> JsValue { L8Error::InvalidRequest(message).into() };
+
+        let known_method = STANDARD_HTTP_METHODS.contains(&self.method.as_str())
+            || InMemoryCache::is_custom_method(&self.method);
+        if !known_method || hyper::Method::from_bytes(self.method.as_bytes()).is_err() {
+            return Err(L8Error::InvalidMethod(format!(
+                "Invalid HTTP method: {:?}, custom methods must be listed in the customMethods init option",
+                self.method
+            ))
+            .into());
+        }
+
+        if !self.uri.starts_with('/') || self.uri.parse::<hyper::Uri>().is_err() {
+            return Err(invalid(format!("Invalid request URI: {:?}", self.uri)));
+        }
+
+        for (name, value) in &self.headers {
+            if hyper::header::HeaderName::from_bytes(name.as_bytes()).is_err() {
+                return Err(invalid(format!("Invalid header name: {:?}", name)));
+            }
+
+            let value = match value {
+                serde_json::Value::String(val) => val.clone(),
+                other => other.to_string(),
+            };
+            if hyper::header::HeaderValue::from_str(&value).is_err() {
+                return Err(invalid(format!(
+                    "Invalid value for header {:?}: {:?}",
+                    name, value
+                )));
+            }
+        }
+
+        if (self.method == "GET" || self.method == "HEAD") && !self.body.is_empty() {
+            return Err(invalid(format!(
+                "Request with {} method cannot have a body",
+                self.method
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Applies the `credentials` option to the tunneled request. The proxy, not the browser, talks to the backend,
+    /// so the document cookies only reach it when attached here:
+    /// - `omit` removes any `Cookie` header
+    /// - `include` attaches `document_cookie` when the backend is same-site with the document (same scheme and same
+    ///   registrable domain, e.g. `app.example.com` and `api.example.com` but not `a.github.io` and `b.github.io`, or
+    ///   the same host when there is none, like for IPs); the document cookies are first-party and never leave its site
+    /// - `same-origin` (the default) attaches it only when the backend has the document's origin
+    ///
+    /// A `Cookie` header set explicitly is kept unless the mode is `omit`.
+    pub fn apply_credentials(
+        &mut self,
+        backend_base_url: &str,
+        document_cookie: &str,
+        document_origin: Option<&str>,
+    ) {
+        if self.credentials == "omit" {
+            self.headers
+                .retain(|name, _| !name.eq_ignore_ascii_case("cookie"));
+            return;
+        }
+
+        let has_cookie_header = self
+            .headers
+            .keys()
+            .any(|name| name.eq_ignore_ascii_case("cookie"));
+        if document_cookie.is_empty() || has_cookie_header {
+            return;
+        }
+
+        let (Some(document_url), Ok(backend_url)) = (
+            document_origin.and_then(|origin| url::Url::parse(origin).ok()),
+            url::Url::parse(backend_base_url),
+        ) else {
+            return;
+        };
+
+        let same_site = || {
+            // the registrable domain (eTLD+1) per the public suffix list, a public suffix itself has none. IPs have
+            // none either, they are only same-site with themselves
+            let site = |url: &url::Url| match url.host() {
+                Some(url::Host::Domain(domain)) => psl::domain_str(domain).map(str::to_string),
+                _ => None,
+            };
+            let same_site = match (site(&document_url), site(&backend_url)) {
+                (Some(document_site), Some(backend_site)) => document_site == backend_site,
+                (None, None) => document_url.host() == backend_url.host(),
+                _ => false,
+            };
+            document_url.scheme() == backend_url.scheme() && same_site
+        };
+
+        let attach = match self.credentials.as_str() {
+            "include" => same_site(),
+            _ => document_url.origin() == backend_url.origin(),
+        };
+        if attach {
+            self.headers.insert(
+                "Cookie".to_string(),
+                serde_json::Value::String(document_cookie.to_string()),
+            );
+        }
+    }
+
+    /// The CORS preflight the browser would send before this request (see the `emulatePreflight` init config): an
+    /// `OPTIONS` request carrying the method and the non-safelisted headers of the request. `None` for same-origin
+    /// and simple requests, which are never preflighted.
+    pub fn preflight_request(
+        &self,
+        backend_base_url: &str,
+        document_origin: Option<&str>,
+    ) -> Option<L8RequestObject> {
+        let origin = document_origin.unwrap_or("null");
+        let backend_origin = url::Url::parse(backend_base_url)
+            .ok()
+            .map(|url| url.origin().ascii_serialization());
+        if backend_origin.as_deref() == Some(origin) {
+            return None;
+        }
+
+        let mut unsafe_headers = self
+            .headers
+            .iter()
+            .map(|(name, value)| (name.to_ascii_lowercase(), value))
+            .filter(|(name, value)| match name.as_str() {
+                "content-type" => {
+                    let essence = value
+                        .as_str()
+                        .unwrap_or_default()
+                        .split(';')
+                        .next()
+                        .unwrap_or_default()
+                        .trim()
+                        .to_ascii_lowercase();
+                    !CORS_SAFELISTED_CONTENT_TYPES.contains(&essence.as_str())
+                }
+                name => !CORS_SAFELISTED_HEADERS.contains(&name),
+            })
+            .map(|(name, _)| name)
+            .collect::<Vec<_>>();
+        if CORS_SAFELISTED_METHODS.contains(&self.method.as_str()) && unsafe_headers.is_empty() {
+            return None;
+        }
+
+        let mut headers: HashMap<String, serde_json::Value> = HashMap::from([
+            ("Origin".to_string(), origin.into()),
+            (
+                "Access-Control-Request-Method".to_string(),
+                self.method.clone().into(),
+            ),
+        ]);
+        if !unsafe_headers.is_empty() {
+            unsafe_headers.sort();
+            headers.insert(
+                "Access-Control-Request-Headers".to_string(),
+                unsafe_headers.join(",").into(),
+            );
+        }
+
+        Some(L8RequestObject {
+            uri: self.uri.clone(),
+            method: "OPTIONS".to_string(),
+            headers,
+            credentials: self.credentials.clone(),
+            signal: self.signal.clone(),
+            session_key: self.session_key.clone(),
+            ..Default::default()
+        })
+    }
+
+    /// Fails with a `NetworkError`, like the Fetch API does, unless the `response` to this preflight request (see
+    /// `preflight_request`) allows the actual request. Wildcards are not honored for credentialed requests.
+    pub fn check_preflight_response(&self, response: &web_sys::Response) -> Result<(), JsValue> {
+        let requested = |name: &str| {
+            self.headers
+                .get(name)
+                .and_then(|value| value.as_str())
+                .map(str::to_string)
+                .unwrap_or_default()
+        };
+        let allowed = |name: &str| {
+            response
+                .headers()
+                .get(name)
+                .ok()
+                .flatten()
+                .unwrap_or_default()
+        };
+        let allowed_list = |name: &str| {
+            allowed(name)
+                .split(',')
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+                .collect::<Vec<_>>()
+        };
+
+        let credentialed = self.credentials == "include";
+        let allows = |list: &[String], value: &str| {
+            list.iter()
+                .any(|entry| entry.eq_ignore_ascii_case(value) || (entry == "*" && !credentialed))
+        };
+
+        let origin = requested("Origin");
+        let method = requested("Access-Control-Request-Method");
+        let allow_origin = allowed("Access-Control-Allow-Origin");
+        let allowed_methods = allowed_list("Access-Control-Allow-Methods");
+        let allowed_headers = allowed_list("Access-Control-Allow-Headers");
+
+        let disallowed = if !response.ok() {
+            Some(format!(
+                "the preflight response status is {}",
+                response.status()
+            ))
+        } else if allow_origin != origin && (credentialed || allow_origin != "*") {
+            Some(format!("the origin {} is not allowed", origin))
+        } else if credentialed && allowed("Access-Control-Allow-Credentials") != "true" {
+            Some("credentials are not allowed".to_string())
+        } else if !CORS_SAFELISTED_METHODS.contains(&method.as_str())
+            && !allows(&allowed_methods, &method)
+        {
+            Some(format!("the method {} is not allowed", method))
+        } else {
+            requested("Access-Control-Request-Headers")
+                .split(',')
+                .filter(|name| !name.is_empty())
+                .find(|name| !allows(&allowed_headers, name))
+                .map(|name| format!("the header {} is not allowed", name))
+        };
+
+        match disallowed {
+            Some(reason) => Err(L8Error::NetworkError(format!(
+                "The CORS preflight of {} {} failed: {}",
+                method, self.uri, reason
+            ))
+            .into()),
+            None => Ok(()),
+        }
+    }
+
+    /// Fails with `RouteNotAllowed` when the provider has `allowedRoutes` in the init config and none of them matches
+    /// the method and path of the request.
+    pub fn check_route(&self, backend_base_url: &str) -> Result<(), JsValue> {
+        let Some(routes) = InMemoryCache::get_allowed_routes(backend_base_url) else {
+            return Ok(());
+        };
+
+        let path = self.uri.split(['?', '#']).next().unwrap_or_default();
+        if routes.iter().any(|route| route.matches(&self.method, path)) {
+            return Ok(());
+        }
+
+        Err(L8Error::RouteNotAllowed(format!(
+            "{} {} is not an allowed route of {}",
+            self.method, path, backend_base_url
+        ))
+        .into())
+    }
+
+    /// Drops the headers the `headerAllowlist` and `headerDenylist` of the init config forbid to tunnel to the
+    /// provider. Names are matched case-insensitively, the denylist wins over the allowlist.
+    pub fn apply_header_policy(&mut self, backend_base_url: &str) {
+        let (allowlist, denylist) = InMemoryCache::get_header_policy(backend_base_url);
+        let listed = |list: &[String], name: &str| {
+            list.iter().any(|listed| listed.eq_ignore_ascii_case(name))
+        };
+
+        self.headers.retain(|name, _| {
+            let allowed = allowlist
+                .as_deref()
+                .is_none_or(|allowlist| listed(allowlist, name))
+                && !listed(&denylist, name);
+            if !allowed {
+                InMemoryCache::log(
+                    LogLevel::Debug,
+                    &format!("Not tunneling the {} header to {}", name, backend_base_url).into(),
+                );
+            }
+
+            allowed
+        });
+    }
+
+    /// Sets the `Content-Type` header to the MIME type of a `Blob` (or `File`) body, like the Fetch API does, unless
+    /// the caller set one or the blob has no type.
+    pub fn apply_blob_type(&mut self, blob: &web_sys::Blob) {
+        let content_type = blob.type_();
+        let has_content_type = self
+            .headers
+            .keys()
+            .any(|name| name.eq_ignore_ascii_case("content-type"));
+        if content_type.is_empty() || has_content_type {
+            return;
+        }
+
+        self.headers.insert(
+            "Content-Type".to_string(),
+            serde_json::Value::String(content_type),
+        );
+    }
+
+    /// Sends a plain object body as JSON, setting the `Content-Type` to `application/json` when the caller set none.
+    /// Callers often pass the object itself instead of `JSON.stringify`ing it. With any other `Content-Type` the
+    /// object is converted with its `toString()`, like the Fetch API does.
+    pub fn apply_object_body(&mut self, object: &js_sys::Object) -> Result<(), JsValue> {
+        let content_type = self
+            .headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-type"))
+            .map(|(_, value)| value.as_str().unwrap_or_default().to_string());
+        let is_json = content_type.as_deref().is_none_or(|content_type| {
+            content_type
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .eq_ignore_ascii_case("application/json")
+        });
+
+        if !is_json {
+            self.body = String::from(js_sys::Object::to_string(object)).into_bytes();
+            return Ok(());
+        }
+
+        let json = js_sys::JSON::stringify(object).map_err(|e| {
+            L8Error::InvalidRequest(format!("Failed to serialize the body to JSON: {:?}", e))
+        })?;
+        self.body = String::from(json).into_bytes();
+        if content_type.is_none() {
+            self.headers.insert(
+                "Content-Type".to_string(),
+                serde_json::Value::String("application/json".to_string()),
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Names a `File` body with a `Content-Disposition: attachment` header, which the raw bytes of the body otherwise
+    /// lose, unless the caller set one. Non-ASCII names are sent as an RFC 6266 `filename*` along with an ASCII
+    /// fallback.
+    pub fn apply_file_name(&mut self, file: &web_sys::File) {
+        let name = file.name();
+        let has_content_disposition = self
+            .headers
+            .keys()
+            .any(|header| header.eq_ignore_ascii_case("content-disposition"));
+        if name.is_empty() || has_content_disposition {
+            return;
+        }
+
+        let mut content_disposition = format!(
+            "attachment; filename=\"{}\"",
+            utils::escape(&name.replace(|c: char| !c.is_ascii() || c.is_ascii_control(), "_"))
+        );
+        if !name.is_ascii() {
+            content_disposition.push_str(&format!(
+                "; filename*=UTF-8''{}",
+                utils::encode_ext_value(&name)
+            ));
+        }
+
+        self.headers.insert(
+            "Content-Disposition".to_string(),
+            serde_json::Value::String(content_disposition),
+        );
+    }
+
+    /// Sets the `Content-Length` of the tunneled request to the size of the body, before any `compressBody`
+    /// compression since the proxy forwards the decoded body. An explicit `Content-Length` header is kept, and
+    /// body-less GET and HEAD requests get none.
+    pub fn set_content_length(&mut self) {
+        let has_content_length = self
+            .headers
+            .keys()
+            .any(|name| name.eq_ignore_ascii_case("content-length"));
+        let bodyless_method = self.method == "GET" || self.method == "HEAD";
+        if has_content_length || (self.body.is_empty() && bodyless_method) {
+            return;
+        }
+
+        self.headers.insert(
+            "Content-Length".to_string(),
+            serde_json::Value::String(self.body.len().to_string()),
+        );
+    }
+
+    /// Fails with an `AbortError` once the `signal` of the request is aborted, or with a `TimeoutError` when it was
+    /// aborted by its `timeout` (or an `AbortSignal.timeout()`).
+    pub fn check_aborted(&self) -> Result<(), JsValue> {
+        match &self.signal {
+            Some(signal) if signal.aborted() => Err(abort_error(signal, &self.uri)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails when the body is bigger than the `maxRequestBodyBytes` of the init config, the proxy would reject it anyway.
+    pub fn check_body_size(&self) -> Result<(), JsValue> {
+        match InMemoryCache::get_max_request_body_bytes() {
+            Some(max) if self.body.len() > max as usize => Err(L8Error::InvalidRequest(format!(
+                "Request body of {} bytes exceeds the maxRequestBodyBytes limit of {} bytes",
+                self.body.len(),
+                max
+            ))
+            .into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Serializes the request into the JSON payload that gets encrypted. With `compress_body` only the body bytes
+    /// are gzipped, the uri, method and headers stay readable JSON.
+    pub fn to_payload(&self) -> Result<Vec<u8>, JsValue> {
+        let expect_msg =
+            "we expect the L8requestObject to be asserted as json serializable at compile time";
+
+        if !self.compress_body || self.body.is_empty() || self.body_encoding.is_some() {
+            return Ok(serde_json::to_vec(&self).expect_throw(expect_msg));
+        }
+
+        let variant = utils::CompressorVariant::Gzip;
+        let compressed = L8RequestObject {
+            body: variant.compress(&self.body)?,
+            body_encoding: Some(variant.content_encoding().to_string()),
+            ..self.clone()
+        };
+
+        Ok(serde_json::to_vec(&compressed).expect_throw(expect_msg))
+    }
+
+    /// The headers of the request to the proxy, along with the encrypted payload.
+    fn proxy_headers(&self, int_rp_jwt: &str, int_fp_jwt: &str) -> Vec<(&'static str, String)> {
+        let mut headers = vec![
+            ("content-type", "application/json".to_string()),
+            ("int_rp_jwt", int_rp_jwt.to_string()),
+            ("int_fp_jwt", int_fp_jwt.to_string()),
+        ];
+
+        // the proxy performs the upstream request, so it is the one to follow redirects or not
+        if let Some(redirect) = &self.redirect {
+            headers.push(("x-l8-redirect", redirect.clone()));
+        }
+
+        // the proxy must not decode the upstream body either
+        if self.raw_body {
+            headers.push(("x-l8-raw-body", "true".to_string()));
+        }
+
+        headers
+    }
+
+    /// The options of a `keepalive` request to the proxy, which outlives the page for unload beacons. Browsers cap
+    /// the bodies of the in-flight keepalive requests of a page at `KEEPALIVE_BODY_LIMIT` bytes in total.
+    pub fn keepalive_request_init(
+        &self,
+        headers: &[(&str, String)],
+        body: &[u8],
+    ) -> Result<RequestInit, JsValue> {
+        let js_headers = web_sys::Headers::new()?;
+        for (name, value) in headers {
+            js_headers.append(name, value)?;
+        }
+
+        let init = RequestInit::new();
+        init.set_method("POST");
+        init.set_headers(&js_headers);
+        init.set_body(&js_sys::Uint8Array::from(body));
+        init.set_keepalive(true);
+        js_sys::Reflect::set(&init, &"credentials".into(), &"include".into())?;
+        Ok(init)
+    }
+
+    /// Sends the request through the tunnel once, the way `fetch` does but without reinitializing the tunnel when
+    /// it fails, with `http_caller` reaching the proxy (e.g. a `MockProxy` to test the full round trip).
+    pub async fn send_through(
+        &self,
+        network_state_open: &NetworkStateOpen,
+        http_caller: impl HttpCaller,
+    ) -> Result<web_sys::Response, JsValue> {
+        let mut metrics = RequestMetrics::default();
+        match self
+            .l8_send(network_state_open, http_caller, false, 1, &mut metrics)
+            .await?
+        {
+            NetworkStateResponse::ProviderResponse(response) => response.reconstruct_js_response(),
+            NetworkStateResponse::ProxyError(err) => Err(err),
+            NetworkStateResponse::Reinitialize => {
+                unreachable!("the tunnel is never reinitialized without `reinitialize_attempt`")
+            }
+        }
+    }
+
+    /// Sends the request using the Layer8 network state, `http_caller` reaching the proxy.
     /// This method can recurse only once to retry sending the request if it fails.
     /// If the request fails again, it will return an error.
+    ///
+    /// The crypto timings of the request are recorded into `metrics`.
     pub(crate) async fn l8_send(
         &self,
         network_state_open: &NetworkStateOpen,
+        http_caller: impl HttpCaller,
         reinitialize_attempt: bool,
+        attempt: u32,
+        metrics: &mut RequestMetrics,
     ) -> Result<NetworkStateResponse, JsValue> {
         let dev_flag = InMemoryCache::get_dev_flag();
-        let data = serde_json::to_vec(&self).expect_throw(
-            "we expect the L8requestObject to be asserted as json serializable at compile time",
-        );
+        self.check_aborted()?;
+        self.check_body_size()?;
+        InspectEvent::Request {
+            method: &self.method,
+            uri: &self.uri,
+            headers: &self.headers,
+            body_len: self.body.len(),
+        }
+        .report();
+        let data = self.to_payload()?;
+
+        *metrics = RequestMetrics {
+            uri: self.uri.clone(),
+            request_bytes: data.len(),
+            ..Default::default()
+        };
 
+        let encrypt_start = utils::now();
         let msg = network_state_open.ntor_encrypt(data)?;
+        metrics.encrypt_ms = utils::now() - encrypt_start;
 
-        let mut req_builder = network_state_open
-            .http_client
-            .post(format!("{}/proxy", network_state_open.forward_proxy_url))
-            .header("content-type", "application/json")
-            .header("int_rp_jwt", network_state_open.int_rp_jwt())
-            .header("int_fp_jwt", network_state_open.int_fp_jwt())
-            .fetch_credentials_include()
-            .body(msg);
+        let proxy_url = format!("{}/proxy", network_state_open.forward_proxy_url);
+        let headers = self.proxy_headers(
+            &network_state_open.int_rp_jwt(),
+            &network_state_open.int_fp_jwt(),
+        );
 
-        if self.body.is_empty() {
-            req_builder = req_builder.header("x-empty-body", "true");
-        }
+        // reqwest has no keepalive option, those requests go through the browser's `fetch` instead
+        let keep_alive = match self.keep_alive {
+            Some(true) if msg.len() > KEEPALIVE_BODY_LIMIT => {
+                InMemoryCache::log(
+                    LogLevel::Warn,
+                    &format!(
+                        "The encrypted body of {} is over the {} bytes browsers allow keepalive requests, sending it without keepalive",
+                        self.uri, KEEPALIVE_BODY_LIMIT
+                    )
+                    .into(),
+                );
+                false
+            }
+            keep_alive => keep_alive.unwrap_or(false),
+        };
 
-        let response_result = req_builder.send().await.inspect_err(|e| {
-            if dev_flag {
-                console::error_1(&format!("Request failed with error: {}", e).into());
+        let response_result = if keep_alive {
+            ProxyResponse::send_keepalive(&proxy_url, self.keepalive_request_init(&headers, &msg)?)
+                .await
+        } else {
+            let mut req_builder = network_state_open
+                .http_client
+                .post(proxy_url)
+                .fetch_credentials_include()
+                .body(msg);
+            for (name, value) in &headers {
+                req_builder = req_builder.header(*name, value);
             }
-        });
+
+            ProxyResponse::send(http_caller, req_builder).await
+        };
+        if let Err(e) = &response_result {
+            InMemoryCache::log(
+                LogLevel::Warn,
+                &format!("Request failed with error: {}", e).into(),
+            );
+        }
+        // the send itself can't be cancelled, a response coming after the abort is dropped
+        self.check_aborted()?;
 
         match response_result {
-            Ok(resp) => Self::handle_response(network_state_open, reinitialize_attempt, resp).await,
+            Ok(resp) => {
+                let response = self
+                    .handle_response(
+                        network_state_open,
+                        reinitialize_attempt,
+                        attempt,
+                        resp,
+                        metrics,
+                    )
+                    .await?;
+
+                if dev_flag && matches!(response, NetworkStateResponse::ProviderResponse(_)) {
+                    InMemoryCache::push_request_metrics(metrics.clone());
+                }
+
+                Ok(response)
+            }
             Err(err) => {
                 // we can reinitialize the network state
-                if reinitialize_attempt {
+                if reinitialize_attempt && should_retry(0, "NetworkError", attempt) {
                     return Ok(NetworkStateResponse::Reinitialize);
                 }
 
-                Err(JsValue::from_str(&format!(
-                    "Failed to send request: {}",
-                    err
-                )))
+                Err(L8Error::NetworkError(format!("Failed to send request: {}", err)).into())
             }
         }
     }
 
     async fn handle_response(
+        &self,
         network_state_open: &NetworkStateOpen,
         reinitialize_attempt: bool,
-        response: reqwest::Response,
+        attempt: u32,
+        response: ProxyResponse,
+        metrics: &mut RequestMetrics,
     ) -> Result<NetworkStateResponse, JsValue> {
-        let dev_flag = InMemoryCache::get_dev_flag();
+        let tunnel_error = is_tunnel_error(response.status.as_u16(), response.tunnel_expired);
+        // any other error response is the provider's, handed over as is since a new tunnel would not change it
+        if response.status >= reqwest::StatusCode::BAD_REQUEST && !tunnel_error {
+            return Ok(NetworkStateResponse::ProviderResponse(
+                response.into_plain_response()?,
+            ));
+        }
 
-        // status >= 400
-        if response.status() >= reqwest::StatusCode::BAD_REQUEST {
-            if dev_flag {
-                console::log_1(
-                    &format!(
-                        "Received error response from the proxy server: {}",
-                        response.status()
-                    )
-                    .into(),
-                );
-            }
+        if tunnel_error {
+            InMemoryCache::log(
+                LogLevel::Warn,
+                &format!(
+                    "Received error response from the proxy server: {}",
+                    response.status
+                )
+                .into(),
+            );
+
+            let status = response.status;
 
             // we can reinitialize the network state
-            if reinitialize_attempt {
+            if reinitialize_attempt && should_retry(status.as_u16(), "ProxyError", attempt) {
                 return Ok(NetworkStateResponse::Reinitialize);
             }
 
-            return Ok(NetworkStateResponse::ProxyError(JsValue::from_str(
-                &format!(
-                    "Unexpected response from the proxy server: {}; With body: {}",
-                    response.status(),
-                    response
-                        .text()
-                        .await
-                        .unwrap_or_else(|_| "No response body".to_string())
-                ),
-            )));
+            return Ok(NetworkStateResponse::ProxyError(
+                L8Error::ProxyError {
+                    status: status.as_u16(),
+                    message: format!(
+                        "Unexpected response from the proxy server: {}; With body: {}",
+                        status,
+                        match &response.body {
+                            Ok(body) => utils::body_preview(body, ERROR_BODY_PREVIEW_BYTES),
+                            Err(_) => "No response body".to_string(),
+                        }
+                    ),
+                }
+                .into(),
+            ));
         }
 
+        let status = response.status.as_u16();
         let body = &response
-            .bytes()
-            .await
-            .map_err(|e| JsValue::from_str(&format!("Failed to read response body: {}", e)))?;
+            .body
+            .map_err(|e| L8Error::NetworkError(format!("Failed to read response body: {}", e)))?;
 
+        let decrypt_start = utils::now();
         let decrypted_response = network_state_open.ntor_decrypt(body)?;
+        metrics.decrypt_ms = utils::now() - decrypt_start;
+        metrics.response_bytes = body.len();
+
+        // the proxy's status says nothing about a response that can't be read
+        let mut l8_response = L8ResponseObject::from_json(&decrypted_response)
+            .map_err(|e| L8Error::NetworkError(format!("Failed to deserialize response: {}", e)))?;
 
-        let l8_response = serde_json::from_slice::<L8ResponseObject>(&decrypted_response)
-            .map_err(|e| JsValue::from_str(&format!("Failed to deserialize response: {}", e)))?;
+        InMemoryCache::log(
+            LogLevel::Debug,
+            &format!("Response: {:?}", l8_response).into(),
+        );
+        InspectEvent::Response {
+            status: l8_response.status,
+            headers: &l8_response.headers,
+            body_len: l8_response.body.len(),
+        }
+        .report();
 
-        if dev_flag {
-            console::log_1(&format!("Response: {:?}", l8_response).into());
+        // with `redirect: "error"` a redirect is a network error, like the Fetch API does.
+        // With `manual` the 3xx response and its `Location` header are handed over as they are.
+        // a `304` answering a revalidation is not a redirect
+        let is_redirect = matches!(l8_response.status, 301 | 302 | 303 | 307 | 308);
+        if self.redirect.as_deref() == Some("error") && is_redirect {
+            return Err(L8Error::NetworkError(format!(
+                "Unexpected redirect ({}) for {} while the redirect mode is \"error\"",
+                l8_response.status, self.uri
+            ))
+            .into());
         }
 
-        // convert L8ResponseObject to web_sys::Response
-        let js_response = l8_response.reconstruct_js_response()?;
-        Ok(NetworkStateResponse::ProviderResponse(js_response))
+        l8_response.drop_head_body(&self.method);
+        // before decoding, which drops the `Content-Length` of the encoded body received
+        if let Some(on_download_progress) = &self.on_download_progress {
+            l8_response.report_download_progress(on_download_progress);
+        }
+
+        l8_response.decode_body(self.raw_body, network_state_open.accepted_encodings());
+        if InMemoryCache::get_filter_exposed_headers() {
+            l8_response.filter_exposed_headers();
+        }
+
+        if let Some(cache_key) = &self.response_cache_key {
+            l8_response = CachedResponse::revalidate(cache_key, &self.headers, l8_response);
+        }
+
+        Ok(NetworkStateResponse::ProviderResponse(l8_response))
+    }
+
+    /// Used in strict mode, fails with an `InvalidRequest` error naming the first key of the options object that
+    /// is not a known fetch option.
+    pub fn reject_unknown_options(options: &RequestInit) -> Result<(), JsValue> {
+        for key in js_sys::Object::keys(options).iter() {
+            let key = key.as_string().unwrap_or_default();
+            if !KNOWN_FETCH_OPTIONS.contains(&key.as_str()) {
+                return Err(L8Error::InvalidRequest(format!(
+                    "Unknown fetch option {:?} (strict mode is enabled)",
+                    key
+                ))
+                .into());
+            }
+        }
+
+        Ok(())
     }
 
     // Ref: <https://developer.mozilla.org/en-US/docs/Web/API/Request>
-    pub fn add_properties(&mut self, options: &web_sys::RequestInit) {
+    /// Fails when the `timeout` option can't be combined with the `signal` of the request.
+    pub fn add_properties(&mut self, options: &web_sys::RequestInit) -> Result<(), JsValue> {
         // body used
         self.body_used = false; // default value
 
@@ -368,7 +1323,71 @@ impl L8RequestObject {
             }
         }
 
-        // signal
-        self.signal = options.get_signal();
+        // signal, combined with the non-standard timeout so that both abort the request the same way
+        let timeout = js_sys::Reflect::get(options, &"timeout".into())
+            .ok()
+            .and_then(|val| val.as_f64());
+        self.signal = match timeout {
+            Some(timeout) => Some(
+                utils::abort_signal_with_timeout(options.get_signal().as_ref(), timeout as i32)
+                    .map_err(|e| {
+                        L8Error::InvalidRequest(format!(
+                            "Failed to combine the signal with the timeout: {:?}",
+                            e
+                        ))
+                    })?,
+            ),
+            None => options.get_signal(),
+        };
+
+        // noRetry, non-standard
+        self.no_retry = js_sys::Reflect::get(&options, &"noRetry".into())
+            .ok()
+            .and_then(|val| val.as_bool())
+            .unwrap_or(false);
+
+        // rawBody, non-standard
+        self.raw_body = js_sys::Reflect::get(&options, &"rawBody".into())
+            .ok()
+            .and_then(|val| val.as_bool())
+            .unwrap_or(false);
+
+        // compressBody, non-standard
+        self.compress_body = js_sys::Reflect::get(&options, &"compressBody".into())
+            .ok()
+            .and_then(|val| val.as_bool())
+            .unwrap_or(false);
+
+        // sessionKey, non-standard
+        self.session_key = js_sys::Reflect::get(&options, &"sessionKey".into())
+            .ok()
+            .and_then(|val| val.as_string());
+
+        // onDownloadProgress, non-standard
+        self.on_download_progress = js_sys::Reflect::get(&options, &"onDownloadProgress".into())
+            .ok()
+            .and_then(|val| val.dyn_into::<js_sys::Function>().ok());
+
+        // queueOffline, non-standard
+        self.queue_offline = js_sys::Reflect::get(&options, &"queueOffline".into())
+            .ok()
+            .and_then(|val| val.as_bool());
+
+        // l8Meta, non-standard
+        if let Ok(meta) = js_sys::Reflect::get(&options, &"l8Meta".into()) {
+            if !meta.is_undefined() && !meta.is_null() {
+                match serde_wasm_bindgen::from_value::<serde_json::Value>(meta) {
+                    Ok(meta) => self.l8_meta = Some(meta),
+                    Err(e) => {
+                        InMemoryCache::log(
+                            LogLevel::Warn,
+                            &format!("Ignoring l8Meta, it is not JSON serializable: {}", e).into(),
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(())
     }
 }
@@ -1,27 +1,380 @@
-use serde::Deserialize;
+use crate::storage::InMemoryCache;
+use crate::types::log_level::LogLevel;
+use crate::utils;
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use wasm_bindgen::{JsValue, throw_str};
-use web_sys::{ResponseInit};
-use crate::utils;
+use web_sys::ResponseInit;
+
+/// What `fetchToStore` resolves with once the response body is saved to IndexedDB.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredResponse {
+    pub store: String,
+    pub key: String,
+    pub status: u16,
+    pub content_type: Option<String>,
+    /// The size of the stored body in bytes.
+    pub size: usize,
+}
 
-#[derive(Deserialize, Debug)]
+/// What the `onDownloadProgress` fetch option is called with.
+#[derive(Serialize, Debug)]
+pub struct DownloadProgress {
+    /// The bytes of the body received so far.
+    pub loaded: usize,
+    /// The size of the body from its `Content-Length` header, `null` when unknown.
+    pub total: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct L8ResponseObject {
     pub status: u16,
     pub status_text: String,
     pub headers: HashMap<String, serde_json::Value>,
     pub body: Vec<u8>,
 
-    /* Below fields are present but not used because ResponseInit does not support */
+    /* Below fields are not supported by ResponseInit, `url` and `redirected` are defined on the response instead */
     #[allow(dead_code)]
     pub ok: bool,
-    #[allow(dead_code)]
+    /// The final URL of the upstream response, after the redirects the proxy followed.
     pub url: String,
-    #[allow(dead_code)]
     pub redirected: bool,
     /* Other fields are ignored because rust and wasm do not support */
 }
 
+/// A decrypted response kept in the in-memory response cache, see `L8RequestObject::response_cache_key`.
+#[derive(Debug, Clone)]
+pub struct CachedResponse {
+    pub response: L8ResponseObject,
+    /// `Date.now()` timestamp past which the response has to be revalidated.
+    pub expires_at: f64,
+    /// Sent as `If-None-Match` to revalidate the stale response, a `304` then serves it again.
+    pub etag: Option<String>,
+    /// The request headers named by the `Vary` header of the response, with their values in the request it answered.
+    /// Only requests with the same values are served the response.
+    pub vary: Vec<(String, Option<serde_json::Value>)>,
+}
+
+impl CachedResponse {
+    /// Wraps the response to a request with `request_headers` for the cache if its status and `Cache-Control`
+    /// header allow it: `200` responses with a `max-age` or an `ETag`, unless `no-store` or `Vary: *`. `no-cache`
+    /// responses are stored but always revalidated.
+    pub fn new(
+        response: &L8ResponseObject,
+        request_headers: &HashMap<String, serde_json::Value>,
+    ) -> Option<Self> {
+        if response.status != 200 {
+            return None;
+        }
+
+        let mut vary = Vec::new();
+        for name in response.header("vary").unwrap_or_default().split(',') {
+            let name = name.trim().to_ascii_lowercase();
+            match name.as_str() {
+                "" => {}
+                // varies on something else than the request headers
+                "*" => return None,
+                _ => {
+                    let value = request_header(request_headers, &name);
+                    vary.push((name, value));
+                }
+            }
+        }
+
+        let cache_control = response.header("cache-control").unwrap_or_default();
+        let mut max_age = None;
+        for directive in cache_control.split(',') {
+            let directive = directive.trim().to_ascii_lowercase();
+            match directive.split_once('=') {
+                _ if directive == "no-store" => return None,
+                _ if directive == "no-cache" => max_age = Some(0),
+                Some(("max-age", seconds)) if max_age.is_none() => {
+                    max_age = seconds.trim_matches('"').parse::<u64>().ok();
+                }
+                _ => {}
+            }
+        }
+
+        let etag = response.header("etag");
+        if max_age.unwrap_or(0) == 0 && etag.is_none() {
+            return None;
+        }
+
+        Some(CachedResponse {
+            response: response.clone(),
+            expires_at: js_sys::Date::now() + max_age.unwrap_or(0) as f64 * 1000.0,
+            etag,
+            vary,
+        })
+    }
+
+    /// Whether the response can answer a request with `request_headers`, see `vary`.
+    pub fn matches(&self, request_headers: &HashMap<String, serde_json::Value>) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| request_header(request_headers, name) == *value)
+    }
+
+    pub fn is_fresh(&self, now: f64) -> bool {
+        now < self.expires_at
+    }
+
+    /// Returns the response cached under `key` for a request with `request_headers`, fresh or not.
+    pub fn lookup(
+        key: &str,
+        request_headers: &HashMap<String, serde_json::Value>,
+    ) -> Option<CachedResponse> {
+        InMemoryCache::get_cached_response(key).filter(|cached| cached.matches(request_headers))
+    }
+
+    /// Handles the response to a request that may have revalidated the response cached under `key` (see `fetch`),
+    /// returning the response to hand over:
+    /// - a `304 Not Modified` serves the cached response again, its headers (and so its freshness) updated with the
+    ///   ones of the `304`
+    /// - any other response replaces the cached one when cacheable
+    pub fn revalidate(
+        key: &str,
+        request_headers: &HashMap<String, serde_json::Value>,
+        response: L8ResponseObject,
+    ) -> L8ResponseObject {
+        if response.status == 304 {
+            let Some(cached) = CachedResponse::lookup(key, request_headers) else {
+                return response;
+            };
+
+            let mut refreshed = cached.response;
+            for (name, value) in response.headers {
+                // they describe the empty body of the `304`, not the cached one
+                if ["content-length", "content-encoding", "transfer-encoding"]
+                    .iter()
+                    .any(|header| name.eq_ignore_ascii_case(header))
+                {
+                    continue;
+                }
+
+                refreshed
+                    .headers
+                    .retain(|cached_name, _| !cached_name.eq_ignore_ascii_case(&name));
+                refreshed.headers.insert(name, value);
+            }
+
+            CachedResponse::store(key, request_headers, &refreshed);
+            return refreshed;
+        }
+
+        CachedResponse::store(key, request_headers, &response);
+        response
+    }
+
+    /// Caches the response to a request with `request_headers` under `key` if it is cacheable, returning whether it
+    /// was.
+    pub fn store(
+        key: &str,
+        request_headers: &HashMap<String, serde_json::Value>,
+        response: &L8ResponseObject,
+    ) -> bool {
+        match CachedResponse::new(response, request_headers) {
+            Some(cached) => {
+                InMemoryCache::cache_response(key, cached);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The value of the request header `name`, whatever its case.
+fn request_header(
+    headers: &HashMap<String, serde_json::Value>,
+    name: &str,
+) -> Option<serde_json::Value> {
+    headers
+        .iter()
+        .find(|(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.clone())
+}
+
+/// The response headers a cross-origin `fetch` always exposes.
+/// Ref: <https://fetch.spec.whatwg.org/#cors-safelisted-response-header-name>
+const CORS_SAFELISTED_RESPONSE_HEADERS: [&str; 7] = [
+    "cache-control",
+    "content-language",
+    "content-length",
+    "content-type",
+    "expires",
+    "last-modified",
+    "pragma",
+];
+
 impl L8ResponseObject {
+    /// Parses the decrypted response. Some legacy upstreams send header values that are not valid UTF-8, those are
+    /// decoded lossily (with U+FFFD) rather than failing the whole response. The body is a byte array in the JSON and
+    /// never affected.
+    pub fn from_json(json: &[u8]) -> Result<Self, serde_json::Error> {
+        if std::str::from_utf8(json).is_ok() {
+            return serde_json::from_slice(json);
+        }
+
+        InMemoryCache::log(
+            LogLevel::Warn,
+            &"The response has header values that are not valid UTF-8, decoding them lossily"
+                .into(),
+        );
+        serde_json::from_str(&String::from_utf8_lossy(json))
+    }
+
+    /// The value of the header, matched case-insensitively, with the values of a repeated header joined by `, `.
+    pub fn header(&self, name: &str) -> Option<String> {
+        let (_, value) = self
+            .headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))?;
+
+        match value {
+            serde_json::Value::String(val) => Some(val.clone()),
+            serde_json::Value::Array(values) => Some(
+                values
+                    .iter()
+                    .filter_map(|val| val.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            ),
+            _ => None,
+        }
+    }
+
+    /// Hands the download progress of the body over to the `onDownloadProgress` fetch option. The body is received and
+    /// decrypted as a whole, so it is reported once, fully loaded. Like `Content-Length`, the bytes are counted as
+    /// received, before any `Content-Encoding` is decoded.
+    pub fn report_download_progress(&self, on_download_progress: &js_sys::Function) {
+        let progress = DownloadProgress {
+            loaded: self.body.len(),
+            total: self
+                .header("content-length")
+                .and_then(|length| length.trim().parse().ok()),
+        };
+
+        // `null` rather than `undefined` for an unknown total, like `ProgressEvent` users expect
+        let serializer = serde_wasm_bindgen::Serializer::new().serialize_missing_as_null(true);
+        match progress.serialize(&serializer) {
+            // the callback is the embedder's concern, it failing must not fail the request
+            Ok(progress) => _ = on_download_progress.call1(&JsValue::NULL, &progress),
+            Err(e) => InMemoryCache::log(
+                LogLevel::Warn,
+                &format!("Failed to serialize download progress: {}", e).into(),
+            ),
+        }
+    }
+
+    /// Emulates the browser's `Access-Control-Expose-Headers` handling: only the CORS-safelisted headers and the ones
+    /// listed by the backend stay readable, `*` exposing them all. `Set-Cookie` is never exposed.
+    pub fn filter_exposed_headers(&mut self) {
+        let exposed = self
+            .headers
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("access-control-expose-headers"))
+            .flat_map(|(_, value)| match value {
+                serde_json::Value::String(val) => vec![val.clone()],
+                serde_json::Value::Array(values) => values
+                    .iter()
+                    .filter_map(|val| val.as_str().map(str::to_string))
+                    .collect(),
+                _ => vec![],
+            })
+            .flat_map(|value| {
+                value
+                    .split(',')
+                    .map(|name| name.trim().to_ascii_lowercase())
+                    .collect::<Vec<_>>()
+            })
+            .filter(|name| !name.is_empty())
+            .collect::<Vec<_>>();
+
+        let expose_all = exposed.iter().any(|name| name == "*");
+        self.headers.retain(|name, _| {
+            let name = name.to_ascii_lowercase();
+            if name == "set-cookie" || name == "set-cookie2" {
+                return false;
+            }
+
+            expose_all
+                || CORS_SAFELISTED_RESPONSE_HEADERS.contains(&name.as_str())
+                || exposed.contains(&name)
+        });
+    }
+
+    /// Decodes the body according to its `Content-Encoding` and drops the headers describing the encoded
+    /// representation, since the JS `Response` is built from the decoded bytes.
+    ///
+    /// With `raw_body` (the `rawBody` fetch option) or an encoding outside of the `accepted` ones negotiated with
+    /// the proxy, the body and headers are left as the upstream sent them. So is the body of a `206 Partial Content`,
+    /// its `Content-Range` counts bytes of the encoded representation and a slice of it can't be decoded on its own.
+    /// A body that fails to decode is handed over as received too, with a warning.
+    pub fn decode_body(&mut self, raw_body: bool, accepted: &[utils::CompressorVariant]) {
+        if raw_body || self.body.is_empty() || self.status == 206 {
+            return;
+        }
+
+        let Some((name, value)) = self
+            .headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("content-encoding"))
+        else {
+            return;
+        };
+
+        let encoding = match value {
+            serde_json::Value::String(val) => val.clone(),
+            serde_json::Value::Array(values) => values
+                .iter()
+                .filter_map(|val| val.as_str())
+                .collect::<Vec<_>>()
+                .join(","),
+            _ => return,
+        };
+
+        // encodings are listed in the order they were applied, only a single one is supported
+        let Some(variant) = utils::CompressorVariant::from_content_encoding(&encoding)
+            .filter(|variant| accepted.contains(variant))
+        else {
+            return;
+        };
+
+        let name = name.clone();
+        match variant.decompress(&self.body) {
+            Ok(body) => self.body = body,
+            Err(e) => {
+                InMemoryCache::log(
+                    LogLevel::Warn,
+                    &format!(
+                        "Failed to decode the {} response body, handing it over as received: {:?}",
+                        encoding, e
+                    )
+                    .into(),
+                );
+                return;
+            }
+        }
+        self.headers.remove(&name);
+        self.headers
+            .retain(|name, _| !name.eq_ignore_ascii_case("content-length"));
+    }
+
+    /// Drops the body of the response to a `HEAD` request, which has none whatever the proxy sends along. The status
+    /// and headers, `Content-Length` included, are kept.
+    pub fn drop_head_body(&mut self, method: &str) {
+        if method == "HEAD" {
+            self.body.clear();
+        }
+    }
+
+    /// Builds the `web_sys::Response` handed to JS. The body is always a fully buffered byte array, never a stream, so
+    /// the response supports `response.clone()` and the clones can be read independently, like caching libraries do.
+    /// An empty body, or any body of a `204`, `205` or `304` (which the `Response` constructor rejects), is a `null` one.
+    ///
+    /// `url` and `redirected` are read-only getters of `Response`, they are shadowed by own properties of the
+    /// response. `response.clone()` doesn't copy them, a clone has an empty `url` and `redirected` is `false`.
     pub fn reconstruct_js_response(&self) -> Result<web_sys::Response, JsValue> {
         let resp_init = ResponseInit::new();
         resp_init.set_status(self.status);
@@ -30,12 +383,18 @@ impl L8ResponseObject {
         let js_headers = utils::hashmap_to_js_headers(&self.headers)?;
         resp_init.set_headers(&js_headers);
 
-        let array = js_sys::Uint8Array::new_with_length(self.body.len() as u32);
-        array.copy_from(&self.body);
+        let null_body = self.body.is_empty() || matches!(self.status, 204 | 205 | 304);
+        let array = (!null_body).then(|| js_sys::Uint8Array::from(self.body.as_slice()));
 
         // we lost Set-Cookie header here
-        match web_sys::Response::new_with_opt_js_u8_array_and_init(Some(&array), &resp_init) {
-            Ok(response) => Ok(response),
+        match web_sys::Response::new_with_opt_js_u8_array_and_init(array.as_ref(), &resp_init) {
+            Ok(response) => {
+                if !self.url.is_empty() {
+                    define_own_property(&response, "url", &self.url.as_str().into())?;
+                    define_own_property(&response, "redirected", &self.redirected.into())?;
+                }
+                Ok(response)
+            }
             Err(err) => {
                 throw_str(&format!(
                     "Failed to construct JS Response: {:?}",
@@ -45,3 +404,16 @@ impl L8ResponseObject {
         }
     }
 }
+
+/// Defines a read-only `name` property on the object, shadowing the getter of its prototype if any.
+fn define_own_property(
+    object: &js_sys::Object,
+    name: &str,
+    value: &JsValue,
+) -> Result<(), JsValue> {
+    let descriptor = js_sys::Object::new();
+    js_sys::Reflect::set(&descriptor, &"value".into(), value)?;
+    js_sys::Reflect::set(&descriptor, &"enumerable".into(), &true.into())?;
+    js_sys::Reflect::define_property(object, &name.into(), &descriptor)?;
+    Ok(())
+}
@@ -5,12 +5,34 @@ use wasm_bindgen::prelude::wasm_bindgen;
 #[wasm_bindgen(getter_with_clone)]
 pub struct ServiceProvider {
     pub url: String,
-    _options: Option<js_sys::Object>, // for now, options is just any object including empty
+    options: Option<js_sys::Object>, // for now, options is just any object including empty
 }
 
 #[wasm_bindgen]
 impl ServiceProvider {
-    pub fn new(url: String, _options: Option<js_sys::Object>) -> Self {
-        ServiceProvider { url, _options }
+    pub fn new(url: String, options: Option<js_sys::Object>) -> Self {
+        ServiceProvider { url, options }
+    }
+}
+
+impl ServiceProvider {
+    /// The handshake priority read from `options.priority`, providers with a higher priority
+    /// are connected first. Defaults to `0` when absent or not a number.
+    pub fn priority(&self) -> i32 {
+        self.options
+            .as_ref()
+            .and_then(|options| js_sys::Reflect::get(options, &"priority".into()).ok())
+            .and_then(|val| val.as_f64())
+            .map(|val| val as i32)
+            .unwrap_or(0)
+    }
+
+    /// The session read from `options.sessionKey`, to register several sessions for one base url. Requests
+    /// pick one with the `sessionKey` fetch option.
+    pub fn session_key(&self) -> Option<String> {
+        self.options
+            .as_ref()
+            .and_then(|options| js_sys::Reflect::get(options, &"sessionKey".into()).ok())
+            .and_then(|val| val.as_string())
     }
 }
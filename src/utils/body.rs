@@ -1,8 +1,8 @@
-use js_sys::Uint8Array;
-use wasm_bindgen::{JsCast, JsValue, UnwrapThrowExt};
-use web_sys::console;
 use crate::storage::InMemoryCache;
-use crate::utils::{escape, normalize_linefeeds};
+use crate::types::{error::L8Error, log_level::LogLevel};
+use crate::utils::{encode_ext_value, escape, normalize_linefeeds};
+use js_sys::Uint8Array;
+use wasm_bindgen::{JsCast, JsValue, UnwrapThrowExt, prelude::wasm_bindgen};
 
 /// Converts an instance of `web_sys::FormData` to a `Uint8Array`
 ///
@@ -21,70 +21,48 @@ pub async fn parse_form_data_to_array(
     boundary: &str,
 ) -> Result<Vec<u8>, JsValue> {
     let prefix = format!("--{}\r\nContent-Disposition: form-data", boundary);
-    let mut blob_parts = Vec::new();
-    let rn = Uint8Array::from(&[13, 10][..]); // '\r\n'
+    // the headers of every part, the index of its contents in `reads` for blob parts
+    let mut parts: Vec<(String, Option<u32>)> = Vec::new();
+    // every blob is read at once, `Promise.all` keeps them in the order of the form
+    let reads = js_sys::Array::new();
 
     // for (const [name, value] of inputFormData)
     for entry in form.entries() {
         let val = js_sys::Array::from(&entry?);
         let key = val.get(0).as_string().ok_or_else(|| {
-            JsValue::from_str("Expected first element of FormData entry to be a string")
+            L8Error::InvalidRequest(
+                "Expected first element of FormData entry to be a string".to_string(),
+            )
         })?;
         let value = val.get(1);
 
         // form field values
         if let Some(value) = value.as_string() {
-            // String value
-            let chunk_str = format!(
-                "{}; name=\"{}\"\r\n\r\n{}\r\n",
-                prefix,
-                escape(&normalize_linefeeds(&key)),
-                normalize_linefeeds(&value)
-            );
-
-            let chunk = chunk_str.as_bytes();
-            blob_parts.extend_from_slice(chunk);
-
+            parts.push((string_part(&prefix, &key, &value), None));
             continue;
         }
 
-        // getting the name before casting to Blob
-        let filename = js_sys::Reflect::get(&value, &"name".into())
-            .map_err(|e| {
-                JsValue::from_str(&format!(
-                    "Expected to retrieve name property before casting to Blob: {}",
-                    e.as_string().unwrap_or_else(|| "unknown error".to_string())
-                ))
-            })?
-            .as_string()
-            .unwrap_or_default();
-
-        let blob = value.dyn_into::<web_sys::Blob>().map_err(|_| {
-            JsValue::from_str("Expected second type cast of FormData entry to be a Blob")
-        })?;
+        let (filename, blob) = form_data_blob(value)?;
 
         // Blob values
-        let file_contents = wasm_bindgen_futures::JsFuture::from(blob.array_buffer()).await?;
-        let file_contents: Uint8Array = Uint8Array::new(&file_contents);
-
-        let content_type = blob.type_();
-
-        let chunk_str = format!(
-            "{}; name=\"{}\"{}Content-Type: {}\r\n\r\n",
-            prefix,
-            escape(&normalize_linefeeds(&key)),
-            if !filename.is_empty() {
-                format!("; filename=\"{}\"\r\n", escape(&filename))
-            } else {
-                "\r\n".to_string()
-            },
-            content_type
-        );
-
-        let chunk = chunk_str.as_bytes();
-        blob_parts.extend_from_slice(chunk);
-        blob_parts.extend_from_slice(&file_contents.to_vec());
-        blob_parts.extend_from_slice(&rn.to_vec()); // \r\n
+        let index = reads.push(&blob.array_buffer()) - 1;
+        parts.push((
+            blob_part_headers(&prefix, &key, &filename, &blob.type_()),
+            Some(index),
+        ));
+    }
+
+    let file_contents = wasm_bindgen_futures::JsFuture::from(js_sys::Promise::all(&reads)).await?;
+    let file_contents = js_sys::Array::from(&file_contents);
+
+    let mut blob_parts = Vec::new();
+    for (chunk_str, read) in parts {
+        blob_parts.extend_from_slice(chunk_str.as_bytes());
+
+        if let Some(index) = read {
+            blob_parts.extend_from_slice(&Uint8Array::new(&file_contents.get(index)).to_vec());
+            blob_parts.extend_from_slice(b"\r\n");
+        }
     }
 
     let chunk = format!("--{}--", boundary);
@@ -93,18 +71,142 @@ pub async fn parse_form_data_to_array(
     Ok(blob_parts)
 }
 
+/// Computes the exact byte length of the multipart body `parse_form_data_to_array` would build for the same
+/// form and boundary, without reading any of the blobs. Useful to set `Content-Length` or progress totals
+/// ahead of time.
+#[wasm_bindgen(js_name = "multipartContentLength")]
+pub fn multipart_content_length(form: &web_sys::FormData, boundary: &str) -> Result<f64, JsValue> {
+    let prefix = format!("--{}\r\nContent-Disposition: form-data", boundary);
+    let mut length = 0.0;
+
+    for entry in form.entries() {
+        let val = js_sys::Array::from(&entry?);
+        let key = val.get(0).as_string().ok_or_else(|| {
+            L8Error::InvalidRequest(
+                "Expected first element of FormData entry to be a string".to_string(),
+            )
+        })?;
+        let value = val.get(1);
+
+        if let Some(value) = value.as_string() {
+            length += string_part(&prefix, &key, &value).len() as f64;
+            continue;
+        }
+
+        let (filename, blob) = form_data_blob(value)?;
+        length += blob_part_headers(&prefix, &key, &filename, &blob.type_()).len() as f64;
+        length += blob.size() + 2.0; // contents and \r\n
+    }
+
+    length += format!("--{}--", boundary).len() as f64;
+    Ok(length)
+}
+
+/// The whole part of a string field, with its trailing `\r\n`.
+fn string_part(prefix: &str, key: &str, value: &str) -> String {
+    format!(
+        "{}; name=\"{}\"\r\n\r\n{}\r\n",
+        prefix,
+        escape(&normalize_linefeeds(key)),
+        normalize_linefeeds(value)
+    )
+}
+
+/// The headers of a blob part, up to the blank line preceding its contents.
+fn blob_part_headers(prefix: &str, key: &str, filename: &str, content_type: &str) -> String {
+    format!(
+        "{}; name=\"{}\"{}Content-Type: {}\r\n\r\n",
+        prefix,
+        escape(&normalize_linefeeds(key)),
+        if !filename.is_empty() {
+            format!("{}\r\n", filename_params(filename))
+        } else {
+            "\r\n".to_string()
+        },
+        content_type
+    )
+}
+
+/// The `filename` parameter of a blob part, escaped like undici does. A non-ASCII name, which many servers
+/// mis-decode, is sent as an RFC 5987 `filename*` along with an ASCII fallback.
+fn filename_params(filename: &str) -> String {
+    if filename.is_ascii() {
+        return format!("; filename=\"{}\"", escape(filename));
+    }
+
+    format!(
+        "; filename=\"{}\"; filename*=UTF-8''{}",
+        escape(&filename.replace(|c: char| !c.is_ascii(), "_")),
+        encode_ext_value(filename)
+    )
+}
+
+/// Splits a non-string FormData value into its file name (empty for plain blobs) and the `Blob` itself.
+fn form_data_blob(value: JsValue) -> Result<(String, web_sys::Blob), JsValue> {
+    // getting the name before casting to Blob
+    let filename = js_sys::Reflect::get(&value, &"name".into())
+        .map_err(|e| {
+            L8Error::InvalidRequest(format!(
+                "Expected to retrieve name property before casting to Blob: {}",
+                e.as_string().unwrap_or_else(|| "unknown error".to_string())
+            ))
+        })?
+        .as_string()
+        .unwrap_or_default();
+
+    let blob = value.dyn_into::<web_sys::Blob>().map_err(|_| {
+        L8Error::InvalidRequest(
+            "Expected second type cast of FormData entry to be a Blob".to_string(),
+        )
+    })?;
+
+    Ok((filename, blob))
+}
+
+/// Copies the bytes of an `ArrayBuffer` or of an `ArrayBufferView` (any TypedArray or a `DataView`), `None` for any
+/// other value. Only the bytes a view covers are copied, from its `byteOffset` and for its `byteLength`, not the
+/// whole buffer beneath it.
+pub fn buffer_source_to_bytes(value: &JsValue) -> Option<Vec<u8>> {
+    if let Some(buffer) = value.dyn_ref::<js_sys::ArrayBuffer>() {
+        return Some(Uint8Array::new(buffer).to_vec());
+    }
+
+    if !js_sys::ArrayBuffer::is_view(value) {
+        return None;
+    }
+
+    let property = |name: &str| js_sys::Reflect::get(value, &name.into()).ok();
+    let buffer = property("buffer")?;
+    let byte_offset = property("byteOffset")?.as_f64()? as u32;
+    let byte_length = property("byteLength")?.as_f64()? as u32;
+
+    Some(Uint8Array::new_with_byte_offset_and_length(&buffer, byte_offset, byte_length).to_vec())
+}
+
 // Ref: <https://developer.mozilla.org/en-US/docs/Web/API/ReadableStreamDefaultReader/read#example_1_-_simple_example>
 /// Converts a ReadableStream to a byte vector by reading all chunks from the stream.
 /// This function reads the stream until it is done and accumulates the data into a Vec<u8>.
 ///
-pub async fn readable_stream_to_bytes(stream: web_sys::ReadableStream) -> Result<Vec<u8>, JsValue> {
-    let dev_flag = InMemoryCache::get_dev_flag();
+/// `size_hint`, the expected size of the stream (e.g. a blob size or a `Content-Length`), is allocated up front, no
+/// more than `max_bytes` of it. The buffer grows as chunks come when there's no hint or it can't be allocated.
+///
+/// Fails with `BodyTooLarge` once more than `max_bytes` bytes were read, the stream is then cancelled and the
+/// reader lock released. `None` reads the stream to its end.
+pub async fn readable_stream_to_bytes(
+    stream: web_sys::ReadableStream,
+    size_hint: Option<usize>,
+    max_bytes: Option<usize>,
+) -> Result<Vec<u8>, JsValue> {
     let reader = stream.get_reader();
     let reader = reader
         .dyn_ref::<web_sys::ReadableStreamDefaultReader>()
         .expect_throw("Expected ReadableStreamDefaultReader, already checked");
 
     let mut data = Vec::new();
+    if let Some(size_hint) = size_hint {
+        // a bogus hint must not fail the read, the buffer then grows like without one
+        _ = data.try_reserve_exact(max_bytes.map_or(size_hint, |max| size_hint.min(max)));
+    }
     loop {
         // { done, value }
         // done  - true if the stream has already given you all its data.
@@ -120,9 +222,10 @@ pub async fn readable_stream_to_bytes(stream: web_sys::ReadableStream) -> Result
 
         if done {
             // If done, we break from the loop and return the accumulated data.
-            if dev_flag {
-                console::log_1(&format!("Stream read completed with {} bytes", data.len()).into());
-            }
+            InMemoryCache::log(
+                LogLevel::Debug,
+                &format!("Stream read completed with {} bytes", data.len()).into(),
+            );
 
             break;
         }
@@ -138,6 +241,18 @@ pub async fn readable_stream_to_bytes(stream: web_sys::ReadableStream) -> Result
             )
             .to_vec();
 
+        if let Some(max_bytes) = max_bytes.filter(|max_bytes| data.len() + value.len() > *max_bytes)
+        {
+            // the rest of the stream is not needed, cancelling it lets its source stop producing
+            _ = reader.cancel();
+            reader.release_lock();
+            return Err(L8Error::BodyTooLarge(format!(
+                "The stream holds more than the {} bytes allowed",
+                max_bytes
+            ))
+            .into());
+        }
+
         data.extend_from_slice(&value);
     }
 
@@ -0,0 +1,79 @@
+use crate::types::error::L8Error;
+use std::io::{Read, Write};
+use wasm_bindgen::JsValue;
+
+/// The `Content-Encoding`s the interceptor knows how to decode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressorVariant {
+    Gzip,
+    Deflate,
+}
+
+impl CompressorVariant {
+    /// Every variant the interceptor can decode, in order of preference.
+    pub const ALL: [CompressorVariant; 2] = [CompressorVariant::Gzip, CompressorVariant::Deflate];
+
+    /// The `x-l8-accept-encoding` value advertising the given variants, e.g. `gzip, deflate`.
+    pub fn accept_encoding(variants: &[CompressorVariant]) -> String {
+        variants
+            .iter()
+            .map(|variant| variant.content_encoding())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Maps a `Content-Encoding` token (case-insensitive) to a variant, `None` for the encodings we can't decode.
+    pub fn from_content_encoding(encoding: &str) -> Option<Self> {
+        match encoding.trim().to_ascii_lowercase().as_str() {
+            "gzip" | "x-gzip" => Some(CompressorVariant::Gzip),
+            "deflate" => Some(CompressorVariant::Deflate),
+            _ => None,
+        }
+    }
+
+    /// The `Content-Encoding` token of the variant.
+    pub fn content_encoding(&self) -> &'static str {
+        match self {
+            CompressorVariant::Gzip => "gzip",
+            CompressorVariant::Deflate => "deflate",
+        }
+    }
+
+    pub fn compress(&self, data: &[u8]) -> Result<Vec<u8>, JsValue> {
+        let result = match self {
+            CompressorVariant::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).and_then(|_| encoder.finish())
+            }
+            CompressorVariant::Deflate => {
+                let mut encoder =
+                    flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).and_then(|_| encoder.finish())
+            }
+        };
+
+        result.map_err(|e| {
+            L8Error::InvalidRequest(format!("Failed to compress {:?} body: {}", self, e)).into()
+        })
+    }
+
+    pub fn decompress(&self, data: &[u8]) -> Result<Vec<u8>, JsValue> {
+        let mut decompressed = Vec::new();
+        let result = match self {
+            CompressorVariant::Gzip => {
+                flate2::read::GzDecoder::new(data).read_to_end(&mut decompressed)
+            }
+            // `deflate` is the zlib format (RFC 1950), not a raw deflate stream
+            CompressorVariant::Deflate => {
+                flate2::read::ZlibDecoder::new(data).read_to_end(&mut decompressed)
+            }
+        };
+
+        result.map_err(|e| {
+            L8Error::NetworkError(format!("Failed to decompress {:?} body: {}", self, e))
+        })?;
+
+        Ok(decompressed)
+    }
+}
@@ -1,15 +1,37 @@
-use std::collections::HashMap;
-use wasm_bindgen::{JsCast, JsValue, UnwrapThrowExt};
-use web_sys::console;
 use crate::storage::InMemoryCache;
+use crate::types::{error::L8Error, log_level::LogLevel};
+use std::collections::HashMap;
+use wasm_bindgen::{JsCast, JsValue, UnwrapThrowExt, prelude::wasm_bindgen};
+
+/// Connection specific headers, they describe the hop between the browser and the proxy and must not be tunneled.
+/// Ref <https://www.rfc-editor.org/rfc/rfc9110#section-7.6.1>
+const HOP_BY_HOP_HEADERS: [&str; 9] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+];
+
+#[wasm_bindgen]
+extern "C" {
+    /// `Object.entries`, surfacing what a `Proxy` trap or a getter throws as an error instead of aborting.
+    #[wasm_bindgen(catch, js_namespace = Object, js_name = entries)]
+    fn try_object_entries(object: &js_sys::Object) -> Result<js_sys::Array, JsValue>;
+}
 
 // Ref <https://developer.mozilla.org/en-US/docs/Web/API/Fetch_API/Using_Fetch#setting_headers>
 // we expect the headers to be either Headers or an Object
+//
+// Header names are canonicalized (see `canonical_header_name`), names differing only by their casing are merged
+// with the last one winning.
 pub fn headers_to_reqwest_headers(
     js_headers: JsValue,
 ) -> Result<HashMap<String, serde_json::Value>, JsValue> {
-    let dev_flag = InMemoryCache::get_dev_flag();
-
     // If the headers are undefined or null, we return an empty HeaderMap
     if js_headers.is_null() || js_headers.is_undefined() {
         return Ok(HashMap::new());
@@ -20,15 +42,17 @@ pub fn headers_to_reqwest_headers(
         return js_headers_to_reqwest_headers(headers);
     }
 
-    if dev_flag {
-        console::log_1(&format!("Headers typeof: {:?}", js_headers.js_typeof()).into());
-    }
+    InMemoryCache::log(
+        LogLevel::Debug,
+        &format!("Headers typeof: {:?}", js_headers.js_typeof()).into(),
+    );
 
     // we can then check if the headers are an instance of js_sys::Object
     if !js_headers.is_object() {
-        return Err(JsValue::from_str(
-            "Invalid headers type. Expected Headers or Object.",
-        ));
+        return Err(L8Error::InvalidRequest(
+            "Invalid headers type. Expected Headers or Object.".to_string(),
+        )
+        .into());
     }
 
     let headers = js_headers
@@ -41,8 +65,16 @@ pub fn headers_to_reqwest_headers(
         return js_headers_to_reqwest_headers(headers);
     }
 
-    // [key, value] item array
-    let entries = js_sys::Object::entries(headers);
+    // [key, value] item array, exotic objects (e.g. a Proxy) may throw while being enumerated
+    let entries = try_object_entries(headers).map_err(|e| {
+        JsValue::from(L8Error::InvalidRequest(format!(
+            "Failed to read the request headers: {}",
+            e.dyn_ref::<js_sys::Error>()
+                .map(|e| String::from(e.message()))
+                .or_else(|| e.as_string())
+                .unwrap_or_else(|| "the headers object is not iterable".to_string())
+        )))
+    })?;
     let mut reqwest_headers = HashMap::new();
     for entry in entries.iter() {
         // [key, value] item array
@@ -58,12 +90,14 @@ pub fn headers_to_reqwest_headers(
             .as_string()
             .expect_throw("Expected header name to be a string");
 
-        let header_value = serde_wasm_bindgen::from_value(value)
-            .map_err(|e| JsValue::from_str(&format!("Failed to convert header value: {}", e)))?;
+        let header_value = serde_wasm_bindgen::from_value(value).map_err(|e| {
+            L8Error::InvalidRequest(format!("Failed to convert header value: {}", e))
+        })?;
 
-        reqwest_headers.insert(header_name, header_value);
+        reqwest_headers.insert(canonical_header_name(&header_name), header_value);
     }
 
+    strip_hop_by_hop_headers(&mut reqwest_headers);
     Ok(reqwest_headers)
 }
 
@@ -82,29 +116,104 @@ fn js_headers_to_reqwest_headers(
             .as_string()
             .expect_throw("Expected header name to be a string");
 
-        let header_value = serde_wasm_bindgen::from_value(value)
-            .map_err(|e| JsValue::from_str(&format!("Failed to convert header value: {}", e)))?;
+        let header_value = serde_wasm_bindgen::from_value(value).map_err(|e| {
+            L8Error::InvalidRequest(format!("Failed to convert header value: {}", e))
+        })?;
 
-        reqwest_headers.insert(header_name, header_value);
+        reqwest_headers.insert(canonical_header_name(&header_name), header_value);
     }
 
+    strip_hop_by_hop_headers(&mut reqwest_headers);
     Ok(reqwest_headers)
 }
 
+/// The headers of a response received through reqwest, a repeated header being the array of its values. Values that
+/// aren't valid UTF-8 are decoded lossily, hop-by-hop headers are dropped.
+pub(crate) fn header_map_to_hashmap(
+    header_map: &hyper::HeaderMap,
+) -> HashMap<String, serde_json::Value> {
+    let mut headers = HashMap::new();
+    for name in header_map.keys() {
+        let mut values = header_map
+            .get_all(name)
+            .iter()
+            .map(|value| {
+                serde_json::Value::String(String::from_utf8_lossy(value.as_bytes()).into())
+            })
+            .collect::<Vec<_>>();
+
+        let value = match values.len() {
+            1 => values.remove(0),
+            _ => serde_json::Value::Array(values),
+        };
+        headers.insert(name.to_string(), value);
+    }
+
+    strip_hop_by_hop_headers(&mut headers);
+    headers
+}
+
+/// The HTTP title-case of a header name (e.g. `content-type` and `CONTENT-TYPE` are `Content-Type`), the casing of
+/// the headers the interceptor sets itself.
+fn canonical_header_name(name: &str) -> String {
+    name.split('-')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => {
+                    first.to_ascii_uppercase().to_string() + &chars.as_str().to_ascii_lowercase()
+                }
+                None => String::new(),
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// Removes the hop-by-hop headers (case-insensitively), along with any header listed in the `Connection` header.
+fn strip_hop_by_hop_headers(headers: &mut HashMap<String, serde_json::Value>) {
+    let connection_options = headers
+        .iter()
+        .filter(|(name, _)| name.eq_ignore_ascii_case("connection"))
+        .filter_map(|(_, value)| value.as_str())
+        .flat_map(|value| value.split(','))
+        .map(|option| option.trim().to_ascii_lowercase())
+        .filter(|option| !option.is_empty())
+        .collect::<Vec<_>>();
+
+    headers.retain(|name, _| {
+        let name = name.to_ascii_lowercase();
+        !HOP_BY_HOP_HEADERS.contains(&name.as_str()) && !connection_options.contains(&name)
+    });
+}
+
 pub fn hashmap_to_js_headers(
     headers: &HashMap<String, serde_json::Value>,
 ) -> Result<web_sys::Headers, JsValue> {
-    let js_headers = web_sys::Headers::new().expect_throw("Failed to create Headers object");
-    for (key, value) in headers.clone() {
-        let value = serde_json::to_string(&value).expect_throw(
-            "we expect the header value to be serializable as a JSON string at compile time",
-        );
-
-        js_headers
-            .append(&key, &value)
-            .expect_throw("Failed to append header to Headers object");
-
-        // console::log_1(&format!("js header: {}: {}", key, value).into());
+    let js_headers = web_sys::Headers::new()?;
+    for (key, value) in headers {
+        // a repeated header is an array of its values
+        let values: Vec<String> = match value {
+            serde_json::Value::Array(values) => values.iter().map(header_value_string).collect(),
+            value => vec![header_value_string(value)],
+        };
+
+        for value in values {
+            // one bad value (eg. outside of ISO-8859-1) must not cost the whole response its headers
+            if let Err(err) = js_headers.append(key, &value) {
+                InMemoryCache::log(
+                    LogLevel::Warn,
+                    &format!("Skipping an invalid {} header value: {:?}", key, err).into(),
+                );
+            }
+        }
     }
     Ok(js_headers)
-}
\ No newline at end of file
+}
+
+fn header_value_string(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::String(val) => val.clone(),
+        value => value.to_string(),
+    }
+}
@@ -1,29 +1,208 @@
-mod print;
-mod headers;
 mod body;
-use wasm_bindgen::{JsCast, JsValue, UnwrapThrowExt};
+mod compression;
+mod headers;
+mod print;
+use sha2::{Digest, Sha256};
+use wasm_bindgen::{
+    JsCast, JsValue, UnwrapThrowExt,
+    prelude::{Closure, wasm_bindgen},
+};
+
+use crate::constants::SHARED_SECRET_FINGERPRINT_BYTES;
+use crate::types::error::L8Error;
 
+pub use body::*;
+pub use compression::*;
 pub use headers::*;
 pub use print::*;
-pub use body::*;
 
-pub(crate) async fn sleep(delay: i32) {
-    let mut cb = |resolve: js_sys::Function, _: js_sys::Function| {
-        _ = web_sys::window()
-            .unwrap()
-            .set_timeout_with_callback_and_timeout_and_arguments_0(&resolve, delay);
-    };
+#[wasm_bindgen]
+extern "C" {
+    /// The global `setTimeout`, available in workers and Node as well as in windows.
+    #[wasm_bindgen(js_name = setTimeout)]
+    fn global_set_timeout(handler: &js_sys::Function, timeout: i32) -> JsValue;
+}
+
+/// Resolves after `delay` milliseconds. Outside of a browser window (workers, Node, SSR) the global `setTimeout` is
+/// used instead of the window's.
+pub async fn sleep(delay: i32) {
+    let mut cb = |resolve: js_sys::Function, _: js_sys::Function| set_timeout(&resolve, delay);
 
     let p = js_sys::Promise::new(&mut cb);
     wasm_bindgen_futures::JsFuture::from(p).await.unwrap();
 }
 
-pub(crate) fn get_base_url(url: &str) -> Result<String, JsValue> {
+/// Calls `handler` after `delay` milliseconds, with the window's `setTimeout` or the global one outside of a window.
+pub(crate) fn set_timeout(handler: &js_sys::Function, delay: i32) {
+    match web_sys::window() {
+        Some(window) => {
+            _ = window.set_timeout_with_callback_and_timeout_and_arguments_0(handler, delay);
+        }
+        None => _ = global_set_timeout(handler, delay),
+    }
+}
+
+/// Combines the caller's `signal` with a timeout into a single signal, like
+/// `AbortSignal.any([signal, AbortSignal.timeout(timeout_ms)])`. It aborts with the reason of `signal` when that one
+/// is aborted first, or with a `TimeoutError` `DOMException` once `timeout_ms` elapsed.
+pub fn abort_signal_with_timeout(
+    signal: Option<&web_sys::AbortSignal>,
+    timeout_ms: i32,
+) -> Result<web_sys::AbortSignal, JsValue> {
+    let controller = web_sys::AbortController::new()?;
+
+    if let Some(signal) = signal {
+        if signal.aborted() {
+            controller.abort_with_reason(&signal.reason());
+            return Ok(controller.signal());
+        }
+
+        let (forwarded, source) = (controller.clone(), signal.clone());
+        let on_abort = Closure::once_into_js(move || forwarded.abort_with_reason(&source.reason()));
+        signal.add_event_listener_with_callback("abort", on_abort.unchecked_ref())?;
+    }
+
+    // aborting an aborted signal is a no-op, the first reason is kept
+    let timed_out = controller.clone();
+    let on_timeout = Closure::once_into_js(move || {
+        if let Ok(reason) = web_sys::DomException::new_with_message_and_name(
+            "The request timed out",
+            "TimeoutError",
+        ) {
+            timed_out.abort_with_reason(&reason);
+        }
+    });
+    set_timeout(on_timeout.unchecked_ref(), timeout_ms);
+
+    Ok(controller.signal())
+}
+
+/// Whether `text` matches the glob `pattern`, where `*` matches any run of characters (possibly empty) and every
+/// other character matches itself.
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let (pattern, text) = (pattern.as_bytes(), text.as_bytes());
+    let (mut p, mut t) = (0, 0);
+    // the position of the last `*` and of the text it was matched against, to backtrack to
+    let mut star: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, t));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == text[t] {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = star {
+            // let the last `*` swallow one more character
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Computes the delay in milliseconds before retrying after the given (1-based) failed attempt.
+/// The delay grows as `base * 2^(attempt-1)`, is capped at `max`, and gets a random jitter of up
+/// to half its value so that many clients recovering from the same outage don't retry in lockstep.
+pub fn backoff_delay(attempt: u32, base: i32, max: i32) -> i32 {
+    let exponent = attempt.saturating_sub(1).min(30);
+    let delay = base.saturating_mul(1 << exponent).min(max).max(0);
+
+    let mut buf = [0u8; 4];
+    let jitter = match getrandom::getrandom(&mut buf) {
+        Ok(()) => u32::from_le_bytes(buf) % (delay as u32 / 2 + 1),
+        Err(_) => 0, // no entropy available, fall back to the plain exponential delay
+    };
+
+    delay + jitter as i32
+}
+
+/// Returns a high resolution timestamp in milliseconds using `performance.now()`, falling back
+/// to `Date.now()` in environments without the Performance API.
+pub fn now() -> f64 {
+    let global = js_sys::global();
+    js_sys::Reflect::get(&global, &"performance".into())
+        .ok()
+        .filter(|performance| performance.is_object())
+        .and_then(|performance| {
+            let now = js_sys::Reflect::get(&performance, &"now".into()).ok()?;
+            now.dyn_ref::<js_sys::Function>()?
+                .call0(&performance)
+                .ok()?
+                .as_f64()
+        })
+        .unwrap_or_else(js_sys::Date::now)
+}
+
+/// The protocol of the page (e.g. `https:`), `None` outside of documents and workers, e.g. under Node.
+pub fn page_protocol() -> Option<String> {
+    js_sys::Reflect::get(&js_sys::global(), &"location".into())
+        .ok()
+        .filter(|location| location.is_object())
+        .and_then(|location| js_sys::Reflect::get(&location, &"protocol".into()).ok())
+        .and_then(|protocol| protocol.as_string())
+}
+
+/// Fails when a `https:` page would connect to a plain `http:` forward proxy, which the browser blocks as mixed content.
+/// Only the proxy connection is checked: the backend is reached by the proxy and not by the browser, so a `http:`
+/// backend behind a `https:` proxy (TLS terminated at the proxy) is not mixed content.
+pub fn check_mixed_content(forward_proxy_url: &str) -> Result<(), JsValue> {
+    check_mixed_content_on(page_protocol().as_deref(), forward_proxy_url)
+}
+
+/// `check_mixed_content` for a page served over `page_protocol`.
+pub fn check_mixed_content_on(
+    page_protocol: Option<&str>,
+    forward_proxy_url: &str,
+) -> Result<(), JsValue> {
+    let proxy_url = url::Url::parse(forward_proxy_url)
+        .map_err(|e| L8Error::InvalidRequest(format!("Invalid forward proxy URL: {}", e)))?;
+
+    if page_protocol == Some("https:") && proxy_url.scheme() == "http" {
+        // the browser would block the connection, like any unreachable proxy
+        return Err(L8Error::NetworkError(format!(
+            "Mixed content: the page is served over https but the forward proxy {} uses http",
+            forward_proxy_url
+        ))
+        .into());
+    }
+
+    Ok(())
+}
+
+/// Serializes the key/value pairs as an `application/x-www-form-urlencoded` query string, percent-encoding
+/// reserved characters and keeping the order and repetitions of the pairs.
+pub fn encode_query(params: &[(String, String)]) -> String {
+    url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(params)
+        .finish()
+}
+
+pub fn get_base_url(url: &str) -> Result<String, JsValue> {
     let url =
-        url::Url::parse(url).map_err(|e| JsValue::from_str(&format!("Invalid URL: {}", e)))?;
+        url::Url::parse(url).map_err(|e| L8Error::InvalidRequest(format!("Invalid URL: {}", e)))?;
+
+    // IPv6 literals must stay bracketed, otherwise the port would be ambiguous (eg. `[::1]:8080`)
+    let host = match url.host() {
+        Some(url::Host::Ipv6(addr)) => format!("[{}]", addr),
+        Some(host) => host.to_string(),
+        None => String::new(),
+    };
+
+    // keep the `user:pass@` userinfo, rendered the way `url::Url` serializes the authority so that the
+    // same provider always maps to the same cache key
+    let userinfo = match (url.username(), url.password()) {
+        ("", None) => String::new(),
+        (username, None) => format!("{}@", username),
+        (username, Some(password)) => format!("{}:{}@", username, password),
+    };
 
     // get without query or path fragments
-    let mut base_url = format!("{}://{}", url.scheme(), url.host_str().unwrap_or_default());
+    let mut base_url = format!("{}://{}{}", url.scheme(), userinfo, host);
     if let Some(port) = url.port() {
         base_url = format!("{}:{}", base_url, port);
     }
@@ -31,9 +210,74 @@ pub(crate) fn get_base_url(url: &str) -> Result<String, JsValue> {
     Ok(base_url)
 }
 
+/// Returns the key of the provider's network state: its base url, suffixed with `#<sessionKey>` when the provider
+/// was registered with a `sessionKey` so that several sessions can share one base url. Base urls never have a
+/// fragment, so the suffix can't clash with them.
+pub fn session_cache_key(base_url: &str, session_key: Option<&str>) -> String {
+    match session_key {
+        Some(session_key) if !session_key.is_empty() => format!("{}#{}", base_url, session_key),
+        _ => base_url.to_string(),
+    }
+}
+
+/// Renders an error body for an error message: as is when it is UTF-8 text, otherwise as a hex preview of its
+/// first `max_bytes` bytes.
+pub fn body_preview(body: &[u8], max_bytes: usize) -> String {
+    if body.is_empty() {
+        return "No response body".to_string();
+    }
+
+    if let Ok(text) = std::str::from_utf8(body) {
+        return text.to_string();
+    }
+
+    let hex = body
+        .iter()
+        .take(max_bytes)
+        .map(|byte| format!("{:02x}", byte))
+        .collect::<String>();
+    let truncated = if body.len() > max_bytes { "..." } else { "" };
+
+    format!(
+        "<{} bytes, not UTF-8> hex: {}{}",
+        body.len(),
+        hex,
+        truncated
+    )
+}
+
+/// The cookies readable by the page (`document.cookie`), empty outside of a document (e.g. in a worker).
+pub(crate) fn document_cookie() -> String {
+    js_sys::Reflect::get(&js_sys::global(), &"document".into())
+        .and_then(|document| js_sys::Reflect::get(&document, &"cookie".into()))
+        .ok()
+        .and_then(|cookie| cookie.as_string())
+        .unwrap_or_default()
+}
+
+/// The origin of the calling script (`location.origin`), in windows and workers alike.
+pub(crate) fn global_origin() -> Option<String> {
+    js_sys::Reflect::get(&js_sys::global(), &"location".into())
+        .and_then(|location| js_sys::Reflect::get(&location, &"origin".into()))
+        .ok()
+        .and_then(|origin| origin.as_string())
+}
+
+/// The base url a network state key (see `session_cache_key`) was derived from.
+pub(crate) fn session_base_url(cache_key: &str) -> &str {
+    cache_key.split('#').next().unwrap_or(cache_key)
+}
+
+/// Whether the browser reports being offline (`navigator.onLine` is `false`). Never where there is no `navigator`.
+pub(crate) fn is_offline() -> bool {
+    js_sys::Reflect::get(&js_sys::global(), &"navigator".into())
+        .and_then(|navigator| js_sys::Reflect::get(&navigator, &"onLine".into()))
+        .is_ok_and(|online| online.as_bool() == Some(false))
+}
+
 pub(crate) fn get_uri(url: &str) -> Result<String, JsValue> {
-    let url_object =
-        url::Url::parse(&url).map_err(|e| JsValue::from_str(&format!("Invalid URL: {}", e)))?;
+    let url_object = url::Url::parse(&url)
+        .map_err(|e| L8Error::InvalidRequest(format!("Invalid URL: {}", e)))?;
 
     let mut uri = url_object.path().to_string();
     if let Some(query) = url_object.query() {
@@ -61,6 +305,11 @@ pub fn retrieve_resource_url(resource: &JsValue) -> Result<String, JsValue> {
         ));
     }
 
+    // like the Fetch API, relative urls (e.g. `/api/users`) are relative to the document base
+    if resource.is_string() {
+        resource_url = resolve_relative_url(resource_url);
+    }
+
     if resource.is_instance_of::<web_sys::Request>() {
         resource_url = resource
             .dyn_ref::<web_sys::Request>()
@@ -69,33 +318,95 @@ pub fn retrieve_resource_url(resource: &JsValue) -> Result<String, JsValue> {
     }
 
     if resource_url.is_empty() {
-        return Err(JsValue::from_str(&format!(
+        return Err(L8Error::InvalidRequest(format!(
             "Invalid resource type for fetch. Expected a string, URL object, or Request object. Got: {:?}",
             resource.js_typeof(),
-        )));
+        ))
+        .into());
     }
 
     // validate the URL from string and Request object
     if let Err(err) = web_sys::Url::new(&resource_url) {
         // If the URL is invalid, we throw an error with the details.
-        return Err(JsValue::from_str(&format!(
+        return Err(L8Error::InvalidRequest(format!(
             "Invalid URL: {}. Error: {}",
             resource_url,
             err.as_string()
                 .unwrap_or_else(|| "Unknown error".to_string())
-        )));
+        ))
+        .into());
     }
 
     Ok(resource_url)
 }
 
-fn escape(str: &str) -> String {
+/// Resolves a relative url against the document base (`document.baseURI`, or `location.href` in workers).
+/// Absolute urls, and relative ones where there is no base to resolve them against, are returned untouched.
+fn resolve_relative_url(url: String) -> String {
+    if web_sys::Url::new(&url).is_ok() {
+        return url;
+    }
+
+    document_base_url()
+        .and_then(|base| web_sys::Url::new_with_base(&url, &base).ok())
+        .map(|resolved| resolved.href())
+        .unwrap_or(url)
+}
+
+/// The base url of the calling script: `document.baseURI` in windows, `location.href` in workers.
+fn document_base_url() -> Option<String> {
+    let global = js_sys::global();
+    js_sys::Reflect::get(&global, &"document".into())
+        .ok()
+        .filter(|document| document.is_object())
+        .and_then(|document| js_sys::Reflect::get(&document, &"baseURI".into()).ok())
+        .and_then(|base| base.as_string())
+        .or_else(|| {
+            js_sys::Reflect::get(&global, &"location".into())
+                .ok()
+                .filter(|location| location.is_object())
+                .and_then(|location| js_sys::Reflect::get(&location, &"href".into()).ok())
+                .and_then(|href| href.as_string())
+        })
+}
+
+/// The hex of the first `SHARED_SECRET_FINGERPRINT_BYTES` bytes of the SHA-256 of the secret, short enough to be
+/// compared by eye and telling nothing about the secret.
+pub(crate) fn secret_fingerprint(secret: &[u8]) -> String {
+    Sha256::digest(secret)
+        .iter()
+        .take(SHARED_SECRET_FINGERPRINT_BYTES)
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// Escapes a multipart field name or filename the way the HTML spec and undici do: LF, CR and `"` are
+/// percent-encoded, every other character (non-ASCII included) is kept as is and sent as UTF-8.
+/// Ref: <https://html.spec.whatwg.org/multipage/form-control-infrastructure.html#multipart-form-data>
+pub(crate) fn escape(str: &str) -> String {
     str.replace('\n', "%0A")
         .replace('\r', "%0D")
         .replace('"', "%22")
 }
 
-fn normalize_linefeeds(value: &str) -> String {
-    value.replace("\r\n", "\n").replace('\r', "\n")
+/// Percent-encodes the value of an RFC 5987 extended parameter (e.g. `filename*`), every byte outside of `attr-char`.
+pub(crate) fn encode_ext_value(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' => (byte as char).to_string(),
+            b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
 }
 
+/// Normalizes every line break (CR, LF or CRLF) to CRLF, like undici does for multipart field names and values.
+fn normalize_linefeeds(value: &str) -> String {
+    value
+        .replace("\r\n", "\n")
+        .replace('\r', "\n")
+        .replace('\n', "\r\n")
+}
@@ -0,0 +1,12 @@
+// no `run_in_browser`, these tests run under Node where there is no `window`
+use {l8_intercept::utils::sleep, wasm_bindgen_test::*};
+
+#[wasm_bindgen_test]
+async fn sleep_does_not_need_a_window() {
+    assert!(web_sys::window().is_none());
+
+    let start = js_sys::Date::now();
+    sleep(20).await;
+    // timers may fire a little early on some platforms
+    assert!(js_sys::Date::now() - start >= 15.0);
+}